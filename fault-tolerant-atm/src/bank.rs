@@ -1,7 +1,7 @@
 use tokio::{io::AsyncWriteExt, fs::File};
 use serde::{Serialize, Deserialize};
 use std::collections::BTreeMap;
-use multicast::NodeId;
+use multicast::{NodeId, NodeNames, MessagePriority};
 use std::io::Cursor;
 use log::trace;
 
@@ -19,22 +19,71 @@ pub struct Transaction {
     pub tr: TransactionType
 }
 
+/// Everything the banks multicast to each other.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub enum BankMessage {
+    Transaction(Transaction),
+    /// Sent directly to a member by a bank that joined late, asking for the
+    /// member's balances.
+    SnapshotRequest(NodeId),
+    /// Sent directly in reply to a `SnapshotRequest`.
+    Snapshot(BankSnapshot)
+}
+
+/// The balances of a bank after processing every transaction up to and 
+/// including `position` in the total order.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct BankSnapshot {
+    pub accounts: BTreeMap<String, usize>,
+    pub position: Option<MessagePriority>
+}
+
 pub struct Bank {
     accounts: BTreeMap<String, usize>,
-    latency_log: File
+    /// The position of the last transaction processed
+    position: Option<MessagePriority>,
+    latency_log: File,
+    /// Used to name the node each logged transaction came from.
+    names: NodeNames
 }
 
 impl Bank {
-    pub async fn new() -> Self {
+    pub async fn new(names: NodeNames) -> Self {
         Self {
             accounts: BTreeMap::new(),
-            latency_log: File::create("latencies.log").await.unwrap()
+            position: None,
+            latency_log: File::create("latencies.log").await.unwrap(),
+            names
         }
     }
 
+    pub fn snapshot(&self) -> BankSnapshot {
+        BankSnapshot {
+            accounts: self.accounts.clone(),
+            position: self.position
+        }
+    }
+
+    /// Replace this bank's balances with those of another member.
+    pub fn restore(&mut self, snapshot: BankSnapshot) {
+        trace!("Restoring balances as of {:?}", snapshot.position);
+        self.accounts = snapshot.accounts;
+        self.position = snapshot.position;
+        self.print_balances();
+    }
+
+    /// Whether the transaction at `position` is already reflected in the 
+    /// balances of this bank.
+    pub fn has_processed(&self, position: &MessagePriority) -> bool {
+        self.position.is_some_and(|p| position <= &p)
+    }
+
     async fn log_latency(&mut self, tx: &Transaction) {
         let latency = crate::get_timestamp() - tx.timestamp;
-        let log_line = format!("n{}-t{},{}\n", tx.node_id, tx.id, latency);
+        let log_line = match self.names.name(tx.node_id) {
+            Some(name) => format!("{}-t{},{}\n", name, tx.id, latency),
+            None => format!("n{}-t{},{}\n", tx.node_id, tx.id, latency)
+        };
 
         let mut cursor = Cursor::new(log_line);
         self.latency_log.write_all_buf(&mut cursor).await.unwrap();
@@ -45,29 +94,36 @@ impl Bank {
         for (account, balance) in &self.accounts {
             print!("{account}:{balance} ");
         }
-        println!("");
+        println!();
     }
 
-    pub async fn process_transaction(&mut self, tx: Transaction) {
+    pub async fn process_transaction(&mut self, tx: Transaction, position: MessagePriority) {
         self.log_latency(&tx).await;
+        self.apply_transaction(tx, position);
+        self.print_balances();
+    }
+
+    /// Apply a transaction read back from the delivery log after a restart.
+    pub fn replay_transaction(&mut self, tx: Transaction, position: MessagePriority) {
+        self.apply_transaction(tx, position);
+    }
+
+    fn apply_transaction(&mut self, tx: Transaction, position: MessagePriority) {
+        self.position = Some(position);
         match tx.tr {
             TransactionType::Deposit(person, amt) => {
                 trace!("DEPOSIT {} {}", person, amt);
                 self.accounts.entry(person).and_modify(|curr| *curr += amt).or_insert(amt); 
             },
             TransactionType::Transfer(person1, person2, amt) => {
-                match self.accounts.get(&person1) {
-                    Some(balance1) => if balance1 >= &amt { 
+                if let Some(balance1) = self.accounts.get(&person1) {
+                    if balance1 >= &amt { 
                         trace!("TRANSFER {} -> {} {}", person1, person2, amt);
                         self.accounts.entry(person1).and_modify(|curr| *curr -= amt);
                         self.accounts.entry(person2).and_modify(|curr| *curr += amt).or_insert(amt);
-                    },
-                    None => ()
+                    }
                 }
             }
         }
-
-        self.print_balances();
     }
 }
-
@@ -50,7 +50,7 @@ impl Cli {
         let delimited: Vec <_> = input
             .trim()
             .split_ascii_whitespace()
-            .filter(|term| term.len() > 0)
+            .filter(|term| !term.is_empty())
             .collect();
 
         match delimited[..] {
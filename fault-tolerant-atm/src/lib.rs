@@ -1,8 +1,10 @@
+pub mod transfer;
 pub mod bank;
 pub mod cli;
 
 pub use multicast::{TotalOrderedMulticast, parse_config};
-pub use bank::{Bank, Transaction, TransactionType};
+pub use bank::{Bank, BankMessage, BankSnapshot, Transaction, TransactionType};
+pub use transfer::StateTransfer;
 pub use cli::Cli;
 
 pub fn get_timestamp() -> f64 {
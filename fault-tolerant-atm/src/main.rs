@@ -1,15 +1,81 @@
-use fault_tolerant_atm::{Bank, Cli, TotalOrderedMulticast, parse_config};
-use multicast::{Config, NodeId, Multicast};
-use tokio::select;
-use log::error;
+use fault_tolerant_atm::{Bank, BankMessage, Cli, StateTransfer, TotalOrderedMulticast, parse_config};
+use multicast::{
+    Config, NodeId, Multicast, MulticastBuilder, DeliveryLog, SyncPolicy, WireFormat, BatchConfig, Compression, CompressionConfig,
+    ConnectEvent, QuorumConfig, Dissemination
+};
+use tokio::{select, sync::mpsc::unbounded_channel, time::{self, Instant}};
+use log::{error, info};
+use std::time::Duration;
 
 #[tokio::main]
 async fn main() {
     env_logger::init();
     let args: Vec<String> = std::env::args().collect();
-    if args.len() != 3 {
-        eprintln!("Usage: {} <identifier> <configuration file>", args[0]);
+    let usage = || {
+        eprintln!("Usage: {} <identifier> <configuration file> [--join] [--log <path>] [--key <path>] [--wire-format <format>] [--batch] [--compress lz4|zstd] [--reconnect <secs>] [--quorum <size>] [--dissemination flooding|gossip:<fanout>|tree:<fanout>|lazy]", args[0]);
         std::process::exit(1);
+    };
+    if args.len() < 3 {
+        usage();
+    }
+
+    let mut join = false;
+    let mut log_path = None;
+    let mut key_path = None;
+    let mut wire_format = None;
+    let mut batch = false;
+    let mut compression = None;
+    let mut reconnect_window = None;
+    let mut quorum = None;
+    let mut dissemination = None;
+    let mut flags = args.iter().skip(3);
+    while let Some(flag) = flags.next() {
+        match flag.as_str() {
+            "--join" => join = true,
+            "--log" => match flags.next() {
+                Some(path) => log_path = Some(path.clone()),
+                None => usage()
+            },
+            "--key" => match flags.next() {
+                Some(path) => key_path = Some(path.clone()),
+                None => usage()
+            },
+            "--batch" => batch = true,
+            "--reconnect" => match flags.next().and_then(|secs| secs.parse().ok()) {
+                Some(secs) => reconnect_window = Some(Duration::from_secs(secs)),
+                None => usage()
+            },
+            "--quorum" => match flags.next().and_then(|size| size.parse().ok()) {
+                Some(size) => quorum = Some(size),
+                None => usage()
+            },
+            "--dissemination" => {
+                let strategy = flags.next().map(|s| match s.split_once(':') {
+                    Some((kind, fanout)) => (kind, fanout.parse().ok()),
+                    None => (s.as_str(), None)
+                });
+                match strategy {
+                    Some(("flooding", None)) => dissemination = Some(Dissemination::Flooding),
+                    Some(("lazy", None)) => dissemination = Some(Dissemination::Lazy),
+                    Some(("gossip", Some(fanout))) => dissemination = Some(Dissemination::Gossip { fanout }),
+                    Some(("tree", Some(fanout))) => dissemination = Some(Dissemination::Tree { fanout }),
+                    _ => usage()
+                }
+            },
+            "--compress" => match flags.next().map(String::as_str) {
+                Some("lz4") => compression = Some(Compression::Lz4),
+                Some("zstd") => compression = Some(Compression::Zstd),
+                _ => usage()
+            },
+            "--wire-format" => match flags.next().map(String::as_str) {
+                Some("bincode") => wire_format = Some(WireFormat::Bincode),
+                Some("json") => wire_format = Some(WireFormat::Json),
+                Some("msgpack") => wire_format = Some(WireFormat::MessagePack),
+                Some("cbor") => wire_format = Some(WireFormat::Cbor),
+                _ => usage()
+            },
+            _ => usage()
+        }
     }
 
     let (config, node_id): (Config, NodeId) = match parse_config(&args[2], &args[1]) {
@@ -20,24 +86,134 @@ async fn main() {
         }
     };
 
-    let mut bank = Bank::new().await;
-    let mut multicast = TotalOrderedMulticast::connect(node_id, config, 60).await;
+    let node_count = config.len();
+    let names = config.names();
+    let mut bank = Bank::new(names.clone()).await;
+
+    // Rebuild the balances from the transactions delivered before a restart
+    let log = match log_path {
+        Some(path) => {
+            let replayed = DeliveryLog::replay::<BankMessage, _>(&path).unwrap_or_else(|e| {
+                eprintln!("Failed to read delivery log {}: {}", path, e);
+                std::process::exit(1);
+            });
+            for (msg, position) in replayed {
+                match msg {
+                    BankMessage::Transaction(tx) => bank.replay_transaction(tx, position),
+                    // Written when this node last joined a running group
+                    BankMessage::Snapshot(snapshot) => bank.restore(snapshot),
+                    BankMessage::SnapshotRequest(_) => ()
+                }
+            }
+
+            match DeliveryLog::open(&path, SyncPolicy::Always) {
+                Ok(log) => Some(log),
+                Err(e) => {
+                    eprintln!("Failed to open delivery log {}: {}", path, e);
+                    std::process::exit(1);
+                }
+            }
+        },
+        None => None
+    };
+
+    // Log every attempt to reach another member
+    let (events_snd, mut events) = unbounded_channel();
+    let event_names = names.clone();
+    tokio::spawn(async move {
+        while let Some(event) = events.recv().await {
+            match event {
+                ConnectEvent::AttemptFailed { node_id, address, attempt, error, retry_in: Some(delay) } => 
+                    info!("Attempt {attempt} to reach {} at {address} failed: {error}, retrying in {delay:?}", event_names.describe(node_id)),
+                ConnectEvent::AttemptFailed { node_id, address, attempt, error, retry_in: None } => 
+                    error!("Gave up on reaching {} at {address} after {attempt} attempts: {error}", event_names.describe(node_id)),
+                ConnectEvent::Connected { node_id, address, attempt, elapsed } => 
+                    info!("Reached {} at {address} on attempt {attempt} after {elapsed:?}", event_names.describe(node_id))
+            }
+        }
+    });
+
+    let mut builder = MulticastBuilder::new(node_id, config).connect_events(events_snd);
+    if let Some(format) = wire_format {
+        builder = builder.wire_format(format);
+    }
+    if let Some(log) = log.clone() {
+        builder = builder.delivery_log(log);
+    }
+    if let Some(key) = key_path {
+        builder = builder.tls_private_key(key);
+    }
+    if batch {
+        builder = builder.batching(BatchConfig::default());
+    }
+    if let Some(window) = reconnect_window {
+        builder = builder.reconnect_window(window);
+    }
+    if let Some(size) = quorum {
+        builder = builder.quorum(QuorumConfig { size, wait: Duration::from_secs(5) });
+    }
+    if let Some(dissemination) = dissemination {
+        builder = builder.dissemination(dissemination);
+    }
+    if let Some(algorithm) = compression {
+        builder = builder.compression(CompressionConfig { algorithm, ..Default::default() });
+    }
+    let connection = match join {
+        true => builder.join_total_order().await,
+        false => builder.total_order().await
+    };
+    let mut multicast: TotalOrderedMulticast<BankMessage> = match connection {
+        Ok(m) => m,
+        Err(e) => {
+            eprintln!("Failed to join multicast group: {}", e.with_names(&names));
+            std::process::exit(1);
+        }
+    };
     let mut cli = Cli::new(node_id);
 
+    // A bank that joins late starts from another member's balances
+    let mut transfer = match join {
+        true => Some(StateTransfer::start(&mut multicast, node_id, node_count).await),
+        false => None
+    };
+
     loop {
         select! {
             input = cli.parse_input() => match input {
                 Some(transaction) => {
-                    if let Err(e) = multicast.broadcast(transaction).await {
-                        error!("broadcase error: {e:?}")
+                    if let Err(e) = multicast.broadcast(BankMessage::Transaction(transaction)).await {
+                        error!("Broadcast error: {}", e.with_names(&names))
                     }
                 },
                 None => break
             },
-            delivery = multicast.deliver() => match delivery {
-                Ok(msg) => bank.process_transaction(msg).await,
-                Err(e) => error!("Delivery failure: {e:?}")
+            delivery = multicast.deliver_with_position() => match delivery {
+                Ok((BankMessage::Transaction(tx), Some(position))) => match transfer.as_mut() {
+                    Some(t) => t.buffer(tx, position),
+                    None => bank.process_transaction(tx, position).await
+                },
+                Ok((BankMessage::Transaction(tx), None)) => error!("Ignoring unordered transaction: {tx:?}"),
+                Ok((BankMessage::SnapshotRequest(requester), _)) => {
+                    if let Err(e) = multicast.send_to(BankMessage::Snapshot(bank.snapshot()), requester).await {
+                        error!("Failed to send snapshot to {}: {}", names.describe(requester), e.with_names(&names))
+                    }
+                },
+                Ok((BankMessage::Snapshot(snapshot), _)) => match transfer.take() {
+                    Some(t) => t.finish(&mut bank, snapshot, log.as_ref()).await,
+                    None => error!("Ignoring unrequested snapshot")
+                },
+                Err(e) => error!("Delivery failure: {}", e.with_names(&names))
+            },
+            _ = time::sleep_until(transfer.as_ref().map_or_else(Instant::now, |t| t.deadline())), if transfer.is_some() => {
+                if let Some(t) = transfer.as_mut() {
+                    t.ask_next_member(&mut multicast).await
+                }
             }
         }
     }
-}
\ No newline at end of file
+
+    for (member, stats) in multicast.connection_stats() {
+        info!("Sent {} frames to {} in {} writes ({:.1} per write, {} bytes, compression ratio {:.2})", 
+            stats.frames_written, names.describe(member), stats.writes, stats.mean_batch_size(), stats.bytes_written, stats.compression_ratio());
+    }
+}
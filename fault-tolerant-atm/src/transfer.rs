@@ -0,0 +1,78 @@
+use crate::{Bank, BankMessage, BankSnapshot, Transaction};
+use multicast::{DeliveryLog, Multicast, MessagePriority, NodeId, TotalOrderedMulticast};
+use tokio::time::{Duration, Instant};
+use log::{trace, error};
+
+/// How long to wait for a snapshot before asking another member.
+static SNAPSHOT_TIMEOUT_SECS: u64 = 5;
+
+/// Brings the balances of a bank that joined a running group up to date.
+/// Transactions delivered while waiting for a member's snapshot are held back
+/// and applied after the snapshot, unless the snapshot already reflects them.
+pub struct StateTransfer {
+    node_id: NodeId,
+    node_count: usize,
+    asked: Option<NodeId>,
+    deadline: Instant,
+    buffered: Vec<(Transaction, MessagePriority)>
+}
+
+impl StateTransfer {
+    pub async fn start(multicast: &mut TotalOrderedMulticast<BankMessage>, node_id: NodeId, node_count: usize) -> Self {
+        let mut transfer = Self {
+            node_id,
+            node_count,
+            asked: None,
+            deadline: Instant::now(),
+            buffered: Vec::new()
+        };
+
+        transfer.ask_next_member(multicast).await;
+        transfer
+    }
+
+    /// When to give up on the member that was last asked for a snapshot.
+    pub fn deadline(&self) -> Instant {
+        self.deadline
+    }
+
+    /// Ask the next member that can be reached for a snapshot, wrapping 
+    /// around to the first member if every other one has been asked.
+    pub async fn ask_next_member(&mut self, multicast: &mut TotalOrderedMulticast<BankMessage>) {
+        self.deadline = Instant::now() + Duration::from_secs(SNAPSHOT_TIMEOUT_SECS);
+
+        let first = self.asked.map_or(0, |id| id + 1);
+        let candidates = (first..self.node_count).chain(0..first);
+        for member in candidates.filter(|id| *id != self.node_id) {
+            if multicast.send_to(BankMessage::SnapshotRequest(self.node_id), member).await.is_ok() {
+                trace!("Asked node {} for a snapshot", member);
+                self.asked = Some(member);
+                return;
+            }
+        }
+
+        error!("No member could be asked for a snapshot");
+    }
+
+    pub fn buffer(&mut self, tx: Transaction, position: MessagePriority) {
+        self.buffered.push((tx, position));
+    }
+
+    /// Restore the balances from `snapshot`. The snapshot replaces what `log`
+    /// holds up to its position, so that replaying the log after a restart 
+    /// starts from the same balances.
+    pub async fn finish(self, bank: &mut Bank, snapshot: BankSnapshot, log: Option<&DeliveryLog>) {
+        if let (Some(log), Some(position)) = (log, snapshot.position) {
+            if let Err(e) = log.checkpoint(&BankMessage::Snapshot(snapshot.clone()), position).await {
+                error!("Failed to write the snapshot to the delivery log: {}", e);
+            }
+        }
+
+        bank.restore(snapshot);
+        for (tx, position) in self.buffered {
+            if !bank.has_processed(&position) {
+                bank.process_transaction(tx, position).await;
+            }
+        }
+    }
+}
@@ -1,32 +1,96 @@
 use super::{
-    member::MemberStateMessage, MulticastGroup, IncomingChannel, 
-    config::{Config, NodeId}, Multicast, MulticastError
+    member::{MemberStateMessage, MulticastMemberHandle, data_frame}, MulticastGroup, IncomingChannel, 
+    config::{Config, NodeId}, Multicast, MulticastError, ConnectError,
+    connection_pool::{ConnectionPool, JoinListener}, builder::MulticastBuilder, channel::FlowControl,
+    codec::{WireCodec, WireFormat, CodecError}, stats::{StatsRegistry, ConnectionStats}, builder::Options
 };
-use std::collections::HashSet;
+use tokio_util::bytes::Bytes;
+use std::{collections::{HashMap, HashSet, VecDeque}, time::Duration};
 use async_trait::async_trait;
+use tokio::select;
 use log::{error, trace};
 use serde::{Serialize, de::DeserializeOwned};
 
+/// A best-effort multicast implementation that sends each message directly to
+/// every member of the group. No guarantees are made if the sender crashes
+/// partway through a broadcast.
 pub struct BasicMulticast<M> {
     group: MulticastGroup,
     from_members: IncomingChannel<M>,
-    active_members: HashSet<NodeId>
+    active_members: HashSet<NodeId>,
+    flow: FlowControl,
+    stats: StatsRegistry,
+    format: WireFormat,
+    max_message_size: usize,
+    /// Accepts connections from members reconnecting to this node for as long
+    /// as this is kept.
+    _listener: Option<JoinListener>,
+    /// Messages received from members while `broadcast` or `send_to` waited
+    /// for room in the outgoing queues.
+    backlog: VecDeque<MemberStateMessage<M>>,
+    /// The most messages the backlog holds, or `None` if it is unbounded.
+    backlog_capacity: Option<usize>
 }
 
 impl<M> BasicMulticast<M> {
-    pub(crate) fn new(group: MulticastGroup, from_members: IncomingChannel<M>) -> Self {
+    pub(crate) fn new(group: MulticastGroup, from_members: IncomingChannel<M>, flow: FlowControl, stats: StatsRegistry, 
+            options: &Options, listener: Option<JoinListener>) -> Self {
         let active_members = group.keys().cloned().collect();
-        Self { group, from_members, active_members }
+        Self { 
+            group, 
+            from_members, 
+            active_members, 
+            flow, 
+            stats, 
+            format: options.wire_format, 
+            max_message_size: options.max_message_size, 
+            _listener: listener,
+            backlog: VecDeque::new(),
+            backlog_capacity: options.channel_capacity
+        }
+    }
+
+    pub(crate) async fn connect_with(node_id: NodeId, config: Config) -> Result<Self, ConnectError> 
+            where M: 'static + Send + Serialize + DeserializeOwned {
+        let mut pool = ConnectionPool::new(node_id, &config.options)
+            .connect(&config)
+            .await?;
+
+        trace!("finished connecting to group!");
+
+        let listener = pool.listen_for_reconnections(&config);
+        Ok(BasicMulticast::new(pool.group, pool.from_members, pool.flow, pool.stats, &config.options, listener))
     }
 
     pub(crate) fn broadcast_except(&mut self, msg: M, except: Vec<NodeId>) -> Result<M, MulticastError> where M: Serialize {
-        let to_send = bincode::serialize(&msg).unwrap();
+        let frame = self.encode(&msg)?;
+        self.forward_except(frame, &except).map(|_| msg)
+    }
+
+    /// The frame that carries `msg` to a member.
+    pub(crate) fn encode(&self, msg: &M) -> Result<Bytes, MulticastError> where M: Serialize {
+        data_frame(self.format, self.max_message_size, msg)
+    }
+
+    /// Encode `msg` on its own, to be carried inside another message.
+    pub(crate) fn encode_nested<T>(&self, msg: &T) -> Result<Vec<u8>, MulticastError> where T: Serialize {
+        self.format.encode(msg).map_err(MulticastError::EncodeFailed)
+    }
+
+    /// Decode a message that `encode_nested` encoded.
+    pub(crate) fn decode_nested<T>(&self, bytes: &[u8]) -> Result<T, CodecError> where T: DeserializeOwned {
+        self.format.decode(bytes)
+    }
+
+    /// Send an already encoded frame to every member not in `except`. The
+    /// frame's buffer is shared between the members rather than copied.
+    pub(crate) fn forward_except(&self, frame: Bytes, except: &[NodeId]) -> Result<(), MulticastError> {
         let mut failures = Vec::new();
 
         for handle in self.group.values() {
             if !except.contains(&handle.member_id) {
-                trace!("Sending message to {}: [...{} bytes...]", handle.member_id, to_send.len());
-                if let Err(e) = handle.pass_message(to_send.clone()) {
+                trace!("Sending message to {}: [...{} bytes...]", handle.member_id, frame.len());
+                if let Err(e) = handle.pass_message(frame.clone()) {
                     error!("Failed to pass message to node {} handler: {:?}", handle.member_id, e);
                     failures.push(handle.member_id);
                 }
@@ -34,12 +98,46 @@ impl<M> BasicMulticast<M> {
         }
 
         if failures.is_empty() {
-            Ok(msg)
+            Ok(())
         } else {
             Err(MulticastError::BroadcastError(failures))
         }
     }
 
+    /// Send an already encoded frame to each of `recipients`.
+    pub(crate) fn forward_among(&self, frame: Bytes, recipients: &[NodeId]) -> Result<(), MulticastError> {
+        let except: Vec<NodeId> = self.group.keys().cloned().filter(|member| !recipients.contains(member)).collect();
+        self.forward_except(frame, &except)
+    }
+
+    /// Send `msg` to `recipient` regardless of how full its outgoing queue is.
+    pub(crate) fn send_unchecked(&self, msg: M, recipient: NodeId) -> Result<(), MulticastError> where M: Serialize {
+        let frame = self.encode(&msg)?;
+        self.forward_to(frame, recipient)
+    }
+
+    /// Send an already encoded frame to `recipient` alone.
+    pub(crate) fn forward_to(&self, frame: Bytes, recipient: NodeId) -> Result<(), MulticastError> {
+        let handle = match self.group.get(&recipient) {
+            Some(h) => h,
+            None => return Err(MulticastError::InvalidRecipient(recipient))
+        };
+
+        trace!("Sending message to {}: [...{} bytes...]", handle.member_id, frame.len());
+
+        if let Err(e) = handle.pass_message(frame) {
+            error!("Failed to pass message to node {} handler: {:?}", handle.member_id, e);
+            return Err(MulticastError::ClientDisconnected(recipient));
+        }
+
+        Ok(())
+    }
+
+    pub(crate) fn add_member(&mut self, handle: MulticastMemberHandle) {
+        self.active_members.insert(handle.member_id);
+        self.group.insert(handle.member_id, handle);
+    }
+
     pub fn remove_member(&mut self, member_id: &NodeId) {
         self.group.remove(member_id);
         self.active_members.remove(member_id);
@@ -49,60 +147,94 @@ impl<M> BasicMulticast<M> {
         &self.active_members
     }
 
-    pub(crate) async fn raw_deliver(&mut self) -> Option<MemberStateMessage<M>> {
-        self.from_members.recv().await
+    /// The largest round trip time bound to any member that has been measured.
+    pub(crate) fn max_round_trip(&self) -> Option<Duration> {
+        self.group
+            .values()
+            .filter_map(|handle| *handle.round_trip.borrow())
+            .map(|rtt| rtt.upper_bound())
+            .max()
     }
-}
 
-#[async_trait]
-impl<M> Multicast<M> for BasicMulticast<M> where M: Send + Serialize {
-    async fn connect(_: usize, _: Config, _: u64) -> Self where M: 'static + DeserializeOwned {
-        todo!() 
+    /// What has been sent to each member this node has connected to so far.
+    pub fn connection_stats(&self) -> HashMap<NodeId, ConnectionStats> {
+        self.stats.snapshot()
     }
 
-    async fn broadcast(&mut self, msg: M) -> Result<(), MulticastError> { 
-        let to_send = bincode::serialize(&msg).unwrap();
-        let mut failures = Vec::new();
+    pub(crate) fn stats(&self) -> &StatsRegistry {
+        &self.stats
+    }
 
-        for handle in self.group.values() {
-            trace!("Sending message to {}: [...{} bytes...]", handle.member_id, to_send.len());
-            if let Err(e) = handle.pass_message(to_send.clone()) {
-                error!("Failed to pass message to node {} handler: {:?}", handle.member_id, e);
-                failures.push(handle.member_id);
+    pub(crate) fn flow(&self) -> &FlowControl {
+        &self.flow
+    }
+
+    /// The recipients whose outgoing queue is too full to accept a message
+    /// from the application.
+    pub(crate) fn full_members(&self, recipient: Option<NodeId>) -> Vec<NodeId> {
+        self.flow.full_members(&self.group, recipient)
+    }
+
+    /// Apply the overflow policy to a message from the application, failing
+    /// if it should not be sent.
+    ///
+    /// While waiting, messages from members keep being read into the backlog.
+    /// Otherwise members that are waiting on this node in turn could never
+    /// make room for it. Once the backlog holds as many messages as a queue,
+    /// members are no longer read, just as when `deliver` falls behind.
+    pub(crate) async fn admit(&mut self, recipient: Option<NodeId>) -> Result<(), MulticastError> {
+        loop {
+            let room = self.backlog_capacity.is_none_or(|capacity| self.backlog.len() < capacity);
+            select! {
+                admitted = self.flow.admit(&self.group, recipient) => return admitted,
+                Some(state) = self.from_members.recv(), if room => self.backlog.push_back(state)
             }
         }
+    }
 
-        if failures.is_empty() {
-            Ok(())
-        } else {
-            Err(MulticastError::BroadcastError(failures))
+    pub(crate) async fn raw_deliver(&mut self) -> Option<MemberStateMessage<M>> {
+        match self.backlog.pop_front() {
+            Some(state) => Some(state),
+            None => self.from_members.recv().await
         }
     }
+}
 
-    async fn send_to(&mut self, msg: M, recipient: NodeId) -> Result<(), MulticastError> { 
-        if let Some(handle) = self.group.get(&recipient) {
-            let to_send = bincode::serialize(&msg).unwrap();
-            trace!("Sending message to {}: [...{} bytes...]", handle.member_id, to_send.len());
+#[async_trait]
+impl<M> Multicast<M> for BasicMulticast<M> where M: Send + Serialize {
+    async fn connect(node_id: NodeId, config: Config, timeout_secs: u64) -> Result<Self, ConnectError> where M: 'static + DeserializeOwned {
+        MulticastBuilder::new(node_id, config)
+            .connect_timeout(Duration::from_secs(timeout_secs))
+            .basic()
+            .await
+    }
 
-            if let Err(e) = handle.pass_message(to_send) {
-                error!("Failed to pass message to node {} handler: {:?}", handle.member_id, e);
-                return Err(MulticastError::ClientDisconnected(recipient));
-            }
+    async fn broadcast(&mut self, msg: M) -> Result<(), MulticastError> { 
+        self.admit(None).await?;
+        self.broadcast_except(msg, Vec::new()).map(|_| ())
+    }
 
-            Ok(())
-        } else {
-            Err(MulticastError::InvalidRecipient(recipient))
+    async fn send_to(&mut self, msg: M, recipient: NodeId) -> Result<(), MulticastError> { 
+        if !self.group.contains_key(&recipient) {
+            return Err(MulticastError::InvalidRecipient(recipient));
         }
+        self.admit(Some(recipient)).await?;
+        self.send_unchecked(msg, recipient)
     }
 
     async fn deliver(&mut self) -> Result<M, MulticastError> { 
         use super::member::MemberStateMessageType::*;
         use MulticastError::*;
 
-        match self.from_members.recv().await {
+        match self.raw_deliver().await {
             Some(state) => match state.msg {
-                Message(msg) => Ok(msg), 
-                NetworkError => Err(ClientDisconnected(state.member_id))
+                Message(msg, _) => Ok(msg), 
+                NetworkError => {
+                    self.remove_member(&state.member_id);
+                    Err(ClientDisconnected(state.member_id))
+                },
+                Suspected => Err(ClientSuspected(state.member_id)),
+                Alive => Err(ClientRecovered(state.member_id))
             },
             None => Err(AllClientsDisconnected)
         }
@@ -0,0 +1,367 @@
+use super::{
+    Config, NodeId, ConnectError, BasicMulticast, ReliableMulticast, TotalOrderedMulticast,
+    DeliveryLog, FailureDetectorConfig, FlushTimeout, OverflowPolicy, WireFormat, CompressionConfig, RetryStrategy,
+    ConnectEvent, Dissemination
+};
+use tokio::sync::mpsc::UnboundedSender;
+use serde::{Serialize, de::DeserializeOwned};
+use std::{net::IpAddr, path::PathBuf, time::Duration};
+
+/// How each member handler coalesces outgoing frames into fewer, larger
+/// writes to its socket.
+///
+/// After a frame is queued, the handler waits up to `max_delay` for more
+/// frames and then writes them all at once, or sooner once `max_bytes` are
+/// waiting. This trades a little latency for far fewer system calls and TCP
+/// segments when many small messages are sent.
+#[derive(Clone, Copy, Debug)]
+pub struct BatchConfig {
+    pub max_bytes: usize,
+    pub max_delay: Duration
+}
+
+impl Default for BatchConfig {
+    fn default() -> Self {
+        Self {
+            max_bytes: 64 * 1024,
+            max_delay: Duration::from_millis(1)
+        }
+    }
+}
+
+/// When a node stops waiting for every member and starts with those it has.
+///
+/// Once `size` members, counting this node, are connected, the node waits up
+/// to `wait` for the rest and then starts without them. Members that have not
+/// connected by then are treated as failed: they get no say in the order of
+/// messages, and can come back later by joining the running group, which is
+/// only supported by `TotalOrderedMulticast`. `size` must be a majority of
+/// the group, so that two halves of it cannot start separately.
+#[derive(Clone, Copy, Debug)]
+pub struct QuorumConfig {
+    pub size: usize,
+    pub wait: Duration
+}
+
+impl QuorumConfig {
+    /// Check that `size` is a majority of a group of `node_count` nodes.
+    pub(crate) fn check(&self, node_count: usize) -> Result<(), String> {
+        match self.size * 2 > node_count && self.size <= node_count {
+            true => Ok(()),
+            false => Err(format!("quorum size must be more than half of the {} nodes and at most {}, not {}", 
+                node_count, node_count, self.size))
+        }
+    }
+}
+
+/// The tuning knobs shared by every multicast layer.
+#[derive(Clone, Debug)]
+pub(crate) struct Options {
+    /// How long to wait for the group to form.
+    pub connect_timeout: Duration,
+    /// The group only starts once every member is connected if `None`.
+    pub quorum: Option<QuorumConfig>,
+    pub retry_strategy: RetryStrategy,
+    /// How many times to try reaching a member before giving up on it, or
+    /// `None` to keep trying until `connect_timeout`.
+    pub max_connect_attempts: Option<usize>,
+    /// How long to keep trying to reach each member.
+    pub peer_connect_deadline: Option<Duration>,
+    pub connect_events: Option<UnboundedSender<ConnectEvent>>,
+    /// The interface this node listens on for connections from other members,
+    /// or `None` for every interface of its address's family.
+    pub bind_ip: Option<IpAddr>,
+    /// The number of bytes in the length prefix of every frame.
+    pub length_field_length: usize,
+    pub failure_detector: FailureDetectorConfig,
+    pub flush_timeout: FlushTimeout,
+    /// The capacity of each internal queue, or `None` for unbounded queues.
+    pub channel_capacity: Option<usize>,
+    pub overflow_policy: OverflowPolicy,
+    /// This node's TLS private key, required if the config lists certificates.
+    pub tls_private_key: Option<PathBuf>,
+    /// Members only connect to peers that give the same cluster name.
+    pub cluster_name: String,
+    pub wire_format: WireFormat,
+    /// Frames are written one at a time if `None`.
+    pub batching: Option<BatchConfig>,
+    /// The largest encoded message that is sent or accepted, in bytes.
+    pub max_message_size: usize,
+    /// The most bytes queued for a member before it is treated as failed.
+    pub max_queued_bytes: usize,
+    /// Messages are never compressed if `None`.
+    pub compression: Option<CompressionConfig>,
+    /// How long to try reconnecting to a member after a network failure, or
+    /// `None` to give up on it straight away.
+    pub reconnect_window: Option<Duration>,
+    /// How many of each member's reliably multicast messages are kept to be
+    /// sent again to members that missed them.
+    pub retransmit_buffer: usize,
+    pub dissemination: Dissemination
+}
+
+impl Default for Options {
+    fn default() -> Self {
+        Self {
+            connect_timeout: Duration::from_secs(60),
+            quorum: None,
+            retry_strategy: RetryStrategy::Fixed(Duration::from_millis(100)),
+            max_connect_attempts: None,
+            peer_connect_deadline: None,
+            connect_events: None,
+            bind_ip: None,
+            length_field_length: 4,
+            failure_detector: Default::default(),
+            flush_timeout: Default::default(),
+            channel_capacity: None,
+            overflow_policy: Default::default(),
+            tls_private_key: None,
+            cluster_name: "default".into(),
+            wire_format: Default::default(),
+            batching: None,
+            max_message_size: 8 * 1024 * 1024,
+            max_queued_bytes: 256 * 1024 * 1024,
+            compression: None,
+            reconnect_window: None,
+            retransmit_buffer: 1024,
+            dissemination: Default::default()
+        }
+    }
+}
+
+impl Options {
+    /// Check the options that would otherwise fail later, once members are
+    /// connected.
+    pub(crate) fn validate(&self, node_count: usize) -> Result<(), String> {
+        if !(1..=8).contains(&self.length_field_length) {
+            return Err(format!("length_field_length must be between 1 and 8, not {}", self.length_field_length));
+        }
+        if let Some(quorum) = self.quorum {
+            quorum.check(node_count)?;
+        }
+        Ok(())
+    }
+}
+
+/// Configures and connects any of the multicast layers.
+/// Options not set on the builder keep the values given in the `Config`.
+pub struct MulticastBuilder {
+    node_id: NodeId,
+    config: Config,
+    delivery_log: Option<DeliveryLog>
+}
+
+impl MulticastBuilder {
+    pub fn new(node_id: NodeId, config: Config) -> Self {
+        Self { node_id, config, delivery_log: None }
+    }
+
+    /// How long to wait for every member to connect. Defaults to 60 seconds.
+    pub fn connect_timeout(mut self, timeout: Duration) -> Self {
+        self.config.options.connect_timeout = timeout;
+        self
+    }
+
+    /// Start once a quorum of members is connected, rather than waiting for
+    /// every member. Disabled by default. Connecting fails with
+    /// `ConnectError::InvalidOptions` if the quorum is not a majority.
+    pub fn quorum(mut self, quorum: QuorumConfig) -> Self {
+        self.config.options.quorum = Some(quorum);
+        self
+    }
+
+    /// How to retry connecting to a member that is not yet listening.
+    /// Defaults to retrying every 100 milliseconds.
+    pub fn retry_strategy(mut self, strategy: RetryStrategy) -> Self {
+        self.config.options.retry_strategy = strategy;
+        self
+    }
+
+    /// Give up on reaching a member after this many attempts. By default a
+    /// member is tried until `connect_timeout` expires.
+    pub fn max_connect_attempts(mut self, attempts: usize) -> Self {
+        self.config.options.max_connect_attempts = Some(attempts.max(1));
+        self
+    }
+
+    /// Give up on reaching a member once this long has passed since the first
+    /// attempt, however many attempts that took.
+    pub fn peer_connect_deadline(mut self, deadline: Duration) -> Self {
+        self.config.options.peer_connect_deadline = Some(deadline);
+        self
+    }
+
+    /// Report every attempt to connect to a member, including those made to
+    /// reconnect to it, on `events`.
+    pub fn connect_events(mut self, events: UnboundedSender<ConnectEvent>) -> Self {
+        self.config.options.connect_events = Some(events);
+        self
+    }
+
+    /// The interface to listen on. Defaults to every IPv6 interface if this
+    /// node's host resolves to an IPv6 address, and to every IPv4 interface
+    /// otherwise. Not used by nodes listening on a Unix socket.
+    pub fn bind_ip(mut self, ip: IpAddr) -> Self {
+        self.config.options.bind_ip = Some(ip);
+        self
+    }
+
+    /// The size in bytes of the length prefix of every frame, which limits how
+    /// large a message can be. Must be between 1 and 8, and the same on every
+    /// member, which members check during the handshake. Defaults to 4. Connecting fails with
+    /// `ConnectError::InvalidOptions` if it is out of range.
+    pub fn length_field_length(mut self, bytes: usize) -> Self {
+        self.config.options.length_field_length = bytes;
+        self
+    }
+
+    pub fn failure_detector(mut self, failure_detector: FailureDetectorConfig) -> Self {
+        self.config.options.failure_detector = failure_detector;
+        self
+    }
+
+    /// Only used by `TotalOrderedMulticast`.
+    pub fn flush_timeout(mut self, flush_timeout: FlushTimeout) -> Self {
+        self.config.options.flush_timeout = flush_timeout;
+        self
+    }
+
+    /// Bound every internal queue to `capacity` messages. This covers the
+    /// messages queued for each member, the messages received from members
+    /// that the protocol has not processed yet, and for `TotalOrderedMulticast`
+    /// the messages waiting for `deliver`. Queues are unbounded by default.
+    ///
+    /// When a member's queue is full, `broadcast` and `send_to` follow the
+    /// overflow policy. When the incoming queue is full, members' connections
+    /// are not read until `deliver` catches up, which pushes back on the
+    /// senders. Heartbeats are still exchanged meanwhile, so neither side
+    /// suspects the other just because `deliver` is slow.
+    pub fn channel_capacity(mut self, capacity: usize) -> Self {
+        self.config.options.channel_capacity = Some(capacity);
+        self
+    }
+
+    /// What to do with a message when a recipient's queue is full. Defaults
+    /// to `OverflowPolicy::Block`.
+    pub fn overflow_policy(mut self, policy: OverflowPolicy) -> Self {
+        self.config.options.overflow_policy = policy;
+        self
+    }
+
+    /// The PEM file holding the private key for this node's certificate. Must
+    /// be set if the config lists certificates, and is ignored otherwise.
+    pub fn tls_private_key<P>(mut self, path: P) -> Self where P: Into<PathBuf> {
+        self.config.options.tls_private_key = Some(path.into());
+        self
+    }
+
+    /// The name of the group, which must be the same on every member. Peers
+    /// from a group with a different name are turned away, even if the config
+    /// files are the same. Defaults to `"default"`.
+    pub fn cluster_name<S>(mut self, name: S) -> Self where S: Into<String> {
+        self.config.options.cluster_name = name.into();
+        self
+    }
+
+    /// How messages are encoded on the wire, which must be the same on every
+    /// member. Defaults to `WireFormat::Bincode`, the most compact.
+    pub fn wire_format(mut self, format: WireFormat) -> Self {
+        self.config.options.wire_format = format;
+        self
+    }
+
+    /// Coalesce outgoing frames into batches. Off by default, so every frame
+    /// is written as soon as it is queued.
+    pub fn batching(mut self, batching: BatchConfig) -> Self {
+        self.config.options.batching = Some(batching);
+        self
+    }
+
+    /// The largest message, once encoded, that is sent to or accepted from a
+    /// member, which must be the same on every member. Larger messages are
+    /// refused by `broadcast` and `send_to` with
+    /// `MulticastError::MessageTooLarge`, and a member that sends one anyway
+    /// is disconnected. The limit includes the few bytes each multicast layer
+    /// adds to a message. Defaults to 8 MiB.
+    pub fn max_message_size(mut self, bytes: usize) -> Self {
+        self.config.options.max_message_size = bytes;
+        self
+    }
+
+    /// The most bytes of frames that may wait to be written to a member. This
+    /// bounds each member's queue even when `channel_capacity` does not, and
+    /// covers the frames the protocols send on their own. A member whose queue
+    /// would grow past it has stopped keeping up, and is treated as failed.
+    /// Defaults to 256 MiB.
+    pub fn max_queued_bytes(mut self, bytes: usize) -> Self {
+        self.config.options.max_queued_bytes = bytes;
+        self
+    }
+
+    /// Compress large messages to members that also enabled compression. Off
+    /// by default.
+    pub fn compression(mut self, compression: CompressionConfig) -> Self {
+        self.config.options.compression = Some(compression);
+        self
+    }
+
+    /// Reconnect to a member whose connection fails, for example because of a
+    /// brief network outage, rather than removing it from the group. The
+    /// member is only reported as disconnected if no new connection is made
+    /// within `window`. Messages that were sent but not acknowledged by the
+    /// member before the failure are sent again, so none are lost or
+    /// duplicated. Only used between members that both enabled it. Off by
+    /// default.
+    ///
+    /// A member that crashes is not removed until the window has passed, so
+    /// it cannot rejoin a `TotalOrderedMulticast` group before then.
+    pub fn reconnect_window(mut self, window: Duration) -> Self {
+        self.config.options.reconnect_window = Some(window);
+        self
+    }
+
+    /// How many messages from each member a `ReliableMulticast` keeps after
+    /// delivering them, to send again to members that report missing them.
+    /// Messages every member has delivered are discarded sooner. A member
+    /// that falls further behind than this can no longer catch up. Defaults
+    /// to 1024.
+    pub fn retransmit_buffer(mut self, messages: usize) -> Self {
+        self.config.options.retransmit_buffer = messages.max(1);
+        self
+    }
+
+    /// How reliably multicast messages are passed on through the group, which
+    /// should be the same on every member. Defaults to
+    /// `Dissemination::Flooding`.
+    pub fn dissemination(mut self, dissemination: Dissemination) -> Self {
+        self.config.options.dissemination = dissemination;
+        self
+    }
+
+    /// Append every message a `TotalOrderedMulticast` delivers to `log`.
+    pub fn delivery_log(mut self, log: DeliveryLog) -> Self {
+        self.delivery_log = Some(log);
+        self
+    }
+
+    pub async fn basic<M>(self) -> Result<BasicMulticast<M>, ConnectError>
+            where M: 'static + Send + Serialize + DeserializeOwned {
+        BasicMulticast::connect_with(self.node_id, self.config).await
+    }
+
+    pub async fn reliable<M>(self) -> Result<ReliableMulticast<M>, ConnectError>
+            where M: 'static + Send + Serialize + DeserializeOwned {
+        ReliableMulticast::connect_with(self.node_id, self.config).await
+    }
+
+    pub async fn total_order<M>(self) -> Result<TotalOrderedMulticast<M>, ConnectError>
+            where M: 'static + Send + Serialize + Clone + DeserializeOwned {
+        TotalOrderedMulticast::priv_connect(self.node_id, self.config, self.delivery_log).await
+    }
+
+    /// Join a `TotalOrderedMulticast` group that is already running.
+    pub async fn join_total_order<M>(self) -> Result<TotalOrderedMulticast<M>, ConnectError>
+            where M: 'static + Send + Serialize + Clone + DeserializeOwned {
+        TotalOrderedMulticast::priv_join(self.node_id, self.config, self.delivery_log).await
+    }
+}
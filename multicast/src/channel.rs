@@ -0,0 +1,152 @@
+use super::{MulticastGroup, MulticastError, NodeId};
+use tokio::sync::{
+    mpsc::{self, UnboundedSender, UnboundedReceiver, unbounded_channel, error::SendError}, Notify
+};
+use serde::Deserialize;
+use std::sync::Arc;
+
+/// What `broadcast` and `send_to` do when a recipient's outgoing queue is full.
+#[derive(Clone, Copy, Debug, Default, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum OverflowPolicy {
+    /// Wait until every recipient's queue has room.
+    #[default]
+    Block,
+    /// Discard the message without sending it to any member, and report
+    /// that with `MulticastError::Dropped`.
+    Drop,
+    /// Fail with `MulticastError::Backpressure`, naming the full members.
+    Error
+}
+
+/// The sending half of a channel that is bounded if it was given a capacity.
+pub(crate) enum Sender<T> {
+    Bounded(mpsc::Sender<T>),
+    Unbounded(UnboundedSender<T>)
+}
+
+impl<T> Clone for Sender<T> {
+    fn clone(&self) -> Self {
+        match self {
+            Sender::Bounded(s) => Sender::Bounded(s.clone()),
+            Sender::Unbounded(s) => Sender::Unbounded(s.clone())
+        }
+    }
+}
+
+impl<T> Sender<T> {
+    /// Send `value`, waiting for room if the channel is bounded and full.
+    pub async fn send(&self, value: T) -> Result<(), SendError<T>> {
+        match self {
+            Sender::Bounded(s) => s.send(value).await,
+            Sender::Unbounded(s) => s.send(value)
+        }
+    }
+
+    /// Wait for room to send one value.
+    pub async fn reserve(&self) -> Result<Permit<'_, T>, SendError<()>> {
+        match self {
+            Sender::Bounded(s) => s.reserve().await.map(Permit::Bounded),
+            Sender::Unbounded(s) if s.is_closed() => Err(SendError(())),
+            Sender::Unbounded(s) => Ok(Permit::Unbounded(s))
+        }
+    }
+}
+
+pub(crate) enum Permit<'a, T> {
+    Bounded(mpsc::Permit<'a, T>),
+    Unbounded(&'a UnboundedSender<T>)
+}
+
+impl<T> Permit<'_, T> {
+    pub fn send(self, value: T) {
+        match self {
+            Permit::Bounded(p) => p.send(value),
+            Permit::Unbounded(s) => { let _ = s.send(value); }
+        }
+    }
+}
+
+pub(crate) enum Receiver<T> {
+    Bounded(mpsc::Receiver<T>),
+    Unbounded(UnboundedReceiver<T>)
+}
+
+impl<T> Receiver<T> {
+    pub async fn recv(&mut self) -> Option<T> {
+        match self {
+            Receiver::Bounded(r) => r.recv().await,
+            Receiver::Unbounded(r) => r.recv().await
+        }
+    }
+}
+
+/// Create a channel holding at most `capacity` values, or any number of values
+/// if `capacity` is `None`.
+pub(crate) fn channel<T>(capacity: Option<usize>) -> (Sender<T>, Receiver<T>) {
+    match capacity {
+        Some(capacity) => {
+            let (snd, rcv) = mpsc::channel(capacity.max(1));
+            (Sender::Bounded(snd), Receiver::Bounded(rcv))
+        },
+        None => {
+            let (snd, rcv) = unbounded_channel();
+            (Sender::Unbounded(snd), Receiver::Unbounded(rcv))
+        }
+    }
+}
+
+/// Decides whether a message from the application may be queued for its
+/// recipients.
+///
+/// Only messages the application sends count against the capacity. Messages
+/// the protocols send on their own, like forwarded messages and priority
+/// proposals, are always queued, so their number stays proportional to the
+/// number of application messages that were admitted.
+#[derive(Clone)]
+pub(crate) struct FlowControl {
+    pub capacity: Option<usize>,
+    pub policy: OverflowPolicy,
+    /// Signalled whenever a member's outgoing queue shrinks.
+    pub drained: Arc<Notify>
+}
+
+impl FlowControl {
+    pub fn new(capacity: Option<usize>, policy: OverflowPolicy) -> Self {
+        Self { capacity, policy, drained: Default::default() }
+    }
+
+    /// The recipients whose outgoing queue is full. All members are recipients
+    /// if `recipient` is `None`.
+    pub fn full_members(&self, group: &MulticastGroup, recipient: Option<NodeId>) -> Vec<NodeId> {
+        let capacity = match self.capacity {
+            Some(c) => c,
+            None => return Vec::new()
+        };
+
+        group
+            .values()
+            .filter(|handle| recipient.is_none_or(|r| r == handle.member_id))
+            .filter(|handle| handle.queued() >= capacity)
+            .map(|handle| handle.member_id)
+            .collect()
+    }
+
+    /// Wait until every recipient has room, or fail according to the policy
+    /// if the message should not be sent.
+    pub async fn admit(&self, group: &MulticastGroup, recipient: Option<NodeId>) -> Result<(), MulticastError> {
+        loop {
+            let drained = self.drained.notified();
+            let full = self.full_members(group, recipient);
+            if full.is_empty() {
+                return Ok(());
+            }
+
+            match self.policy {
+                OverflowPolicy::Block => drained.await,
+                OverflowPolicy::Drop => return Err(MulticastError::Dropped(full)),
+                OverflowPolicy::Error => return Err(MulticastError::Backpressure(full))
+            }
+        }
+    }
+}
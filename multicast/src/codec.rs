@@ -0,0 +1,235 @@
+use serde::{Serialize, Deserialize, de::DeserializeOwned};
+use std::fmt;
+
+/// A message that could not be encoded or decoded.
+#[derive(Debug)]
+pub struct CodecError(pub String);
+
+impl fmt::Display for CodecError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&self.0)
+    }
+}
+
+impl std::error::Error for CodecError {}
+
+fn codec_error<E>(error: E) -> CodecError where E: fmt::Display {
+    CodecError(error.to_string())
+}
+
+/// Turns the messages exchanged by members into bytes and back.
+pub trait WireCodec {
+    /// Append the encoding of `msg` to `out`.
+    fn encode_into<M>(&self, msg: &M, out: &mut Vec<u8>) -> Result<(), CodecError> where M: Serialize;
+    fn decode<M>(&self, bytes: &[u8]) -> Result<M, CodecError> where M: DeserializeOwned;
+
+    fn encode<M>(&self, msg: &M) -> Result<Vec<u8>, CodecError> where M: Serialize {
+        let mut encoded = Vec::new();
+        self.encode_into(msg, &mut encoded)?;
+        Ok(encoded)
+    }
+}
+
+/// The compact binary format of the `bincode` crate, which only Rust reads
+/// easily.
+pub struct Bincode;
+
+impl WireCodec for Bincode {
+    fn encode_into<M>(&self, msg: &M, out: &mut Vec<u8>) -> Result<(), CodecError> where M: Serialize {
+        bincode::serialize_into(out, msg).map_err(codec_error)
+    }
+
+    fn decode<M>(&self, bytes: &[u8]) -> Result<M, CodecError> where M: DeserializeOwned {
+        bincode::deserialize(bytes).map_err(codec_error)
+    }
+}
+
+pub struct Json;
+
+impl WireCodec for Json {
+    fn encode_into<M>(&self, msg: &M, out: &mut Vec<u8>) -> Result<(), CodecError> where M: Serialize {
+        serde_json::to_writer(out, msg).map_err(codec_error)
+    }
+
+    fn decode<M>(&self, bytes: &[u8]) -> Result<M, CodecError> where M: DeserializeOwned {
+        serde_json::from_slice(bytes).map_err(codec_error)
+    }
+}
+
+/// MessagePack with structs encoded as maps, so field names are kept.
+pub struct MessagePack;
+
+impl WireCodec for MessagePack {
+    fn encode_into<M>(&self, msg: &M, out: &mut Vec<u8>) -> Result<(), CodecError> where M: Serialize {
+        rmp_serde::encode::write_named(out, msg).map_err(codec_error)
+    }
+
+    fn decode<M>(&self, bytes: &[u8]) -> Result<M, CodecError> where M: DeserializeOwned {
+        rmp_serde::from_slice(bytes).map_err(codec_error)
+    }
+}
+
+pub struct Cbor;
+
+impl WireCodec for Cbor {
+    fn encode_into<M>(&self, msg: &M, out: &mut Vec<u8>) -> Result<(), CodecError> where M: Serialize {
+        ciborium::into_writer(msg, out).map_err(codec_error)
+    }
+
+    fn decode<M>(&self, bytes: &[u8]) -> Result<M, CodecError> where M: DeserializeOwned {
+        ciborium::from_reader(bytes).map_err(codec_error)
+    }
+}
+
+/// The codec a group uses for its messages, which must be the same on every
+/// member. Members check this during the handshake.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum WireFormat {
+    #[default]
+    Bincode,
+    Json,
+    #[serde(alias = "msgpack")]
+    MessagePack,
+    Cbor
+}
+
+impl WireCodec for WireFormat {
+    fn encode_into<M>(&self, msg: &M, out: &mut Vec<u8>) -> Result<(), CodecError> where M: Serialize {
+        match self {
+            WireFormat::Bincode => Bincode.encode_into(msg, out),
+            WireFormat::Json => Json.encode_into(msg, out),
+            WireFormat::MessagePack => MessagePack.encode_into(msg, out),
+            WireFormat::Cbor => Cbor.encode_into(msg, out)
+        }
+    }
+
+    fn decode<M>(&self, bytes: &[u8]) -> Result<M, CodecError> where M: DeserializeOwned {
+        match self {
+            WireFormat::Bincode => Bincode.decode(bytes),
+            WireFormat::Json => Json.decode(bytes),
+            WireFormat::MessagePack => MessagePack.decode(bytes),
+            WireFormat::Cbor => Cbor.decode(bytes)
+        }
+    }
+}
+
+/// Serializes a message that was already encoded on its own as a byte string,
+/// for use with `#[serde(with = "nested")]`. Formats that have byte strings
+/// store it as one rather than as a sequence of numbers.
+pub(crate) mod nested {
+    use serde::{Serializer, Deserializer, de::{self, Visitor, SeqAccess}};
+    use std::fmt;
+
+    pub fn serialize<S>(bytes: &[u8], serializer: S) -> Result<S::Ok, S::Error> where S: Serializer {
+        serializer.serialize_bytes(bytes)
+    }
+
+    pub fn deserialize<'de, D>(deserializer: D) -> Result<Vec<u8>, D::Error> where D: Deserializer<'de> {
+        deserializer.deserialize_byte_buf(BytesVisitor)
+    }
+
+    struct BytesVisitor;
+
+    impl<'de> Visitor<'de> for BytesVisitor {
+        type Value = Vec<u8>;
+
+        fn expecting(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+            f.write_str("an encoded message")
+        }
+
+        fn visit_bytes<E>(self, bytes: &[u8]) -> Result<Vec<u8>, E> where E: de::Error {
+            Ok(bytes.to_vec())
+        }
+
+        fn visit_byte_buf<E>(self, bytes: Vec<u8>) -> Result<Vec<u8>, E> where E: de::Error {
+            Ok(bytes)
+        }
+
+        // JSON has no byte strings, so the bytes arrive as an array
+        fn visit_seq<A>(self, mut seq: A) -> Result<Vec<u8>, A::Error> where A: SeqAccess<'de> {
+            let mut bytes = Vec::with_capacity(seq.size_hint().unwrap_or(0).min(4096));
+            while let Some(byte) = seq.next_element()? {
+                bytes.push(byte);
+            }
+            Ok(bytes)
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    static FORMATS: [WireFormat; 4] = [WireFormat::Bincode, WireFormat::Json, WireFormat::MessagePack, WireFormat::Cbor];
+
+    #[derive(Debug, PartialEq, Serialize, Deserialize)]
+    enum Message {
+        Empty,
+        Transfer { from: String, to: String, amount: i64 },
+        Batch(Vec<Option<u32>>)
+    }
+
+    #[derive(Debug, PartialEq, Serialize, Deserialize)]
+    struct Envelope {
+        #[serde(with = "nested")]
+        msg: Vec<u8>,
+        sequence_num: usize
+    }
+
+    fn messages() -> Vec<Message> {
+        vec![
+            Message::Empty,
+            Message::Transfer { from: "a1".into(), to: "b".into(), amount: -5 },
+            Message::Batch(vec![Some(1), None, Some(u32::MAX)])
+        ]
+    }
+
+    #[test]
+    fn round_trips() {
+        for format in FORMATS {
+            for msg in messages() {
+                let encoded = format.encode(&msg).unwrap();
+                assert_eq!(format.decode::<Message>(&encoded).unwrap(), msg, "{:?}", format);
+            }
+        }
+    }
+
+    #[test]
+    fn encode_into_appends() {
+        for format in FORMATS {
+            let mut out = vec![0xff];
+            format.encode_into(&Message::Empty, &mut out).unwrap();
+            assert_eq!(out[0], 0xff);
+            assert_eq!(format.decode::<Message>(&out[1..]).unwrap(), Message::Empty);
+        }
+    }
+
+    #[test]
+    fn nested_round_trips() {
+        for format in FORMATS {
+            for msg in messages() {
+                let envelope = Envelope { msg: format.encode(&msg).unwrap(), sequence_num: 7 };
+                let decoded: Envelope = format.decode(&format.encode(&envelope).unwrap()).unwrap();
+                assert_eq!(decoded, envelope, "{:?}", format);
+                assert_eq!(format.decode::<Message>(&decoded.msg).unwrap(), msg);
+            }
+        }
+    }
+
+    #[test]
+    fn nested_is_a_byte_string() {
+        let envelope = Envelope { msg: vec![1, 2, 3], sequence_num: 0 };
+        // A bin 8 header in MessagePack, rather than an array of three numbers
+        let packed = WireFormat::MessagePack.encode(&envelope).unwrap();
+        assert!(packed.windows(5).any(|window| window == [0xc4, 3, 1, 2, 3]));
+        assert_eq!(WireFormat::Json.encode(&envelope).unwrap(), br#"{"msg":[1,2,3],"sequence_num":0}"#);
+    }
+
+    #[test]
+    fn reports_bad_input() {
+        for format in FORMATS {
+            assert!(format.decode::<Message>(&[0xc1, 0xff, 0x00]).is_err(), "{:?}", format);
+        }
+    }
+}
@@ -0,0 +1,80 @@
+use serde::{Serialize, Deserialize};
+
+/// The algorithms messages can be compressed with.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Compression {
+    /// Fast, with a modest ratio.
+    Lz4,
+    /// Slower, but usually much smaller.
+    Zstd
+}
+
+/// How a member compresses the messages it sends.
+///
+/// Compression is only used on a connection if both members enabled it, which
+/// they tell each other during the handshake. Messages smaller than
+/// `threshold` bytes once encoded are sent as they are, as are messages that
+/// do not get any smaller.
+#[derive(Clone, Copy, Debug)]
+pub struct CompressionConfig {
+    pub algorithm: Compression,
+    pub threshold: usize
+}
+
+impl Default for CompressionConfig {
+    fn default() -> Self {
+        Self {
+            algorithm: Compression::Lz4,
+            threshold: 512
+        }
+    }
+}
+
+/// Why a compressed message could not be read.
+pub(crate) enum DecompressError {
+    /// It would be larger than the maximum message size once decompressed.
+    TooLarge,
+    Corrupt(String)
+}
+
+impl Compression {
+    /// Every algorithm a member can decompress, so every one it accepts if it
+    /// enabled compression.
+    pub(crate) const ALL: [Compression; 2] = [Compression::Lz4, Compression::Zstd];
+
+    /// Append the compression of `data` to `out`.
+    pub(crate) fn compress_into(self, data: &[u8], out: &mut Vec<u8>) {
+        match self {
+            Compression::Lz4 => out.extend_from_slice(&lz4_flex::compress_prepend_size(data)),
+            // Compressing into memory can only fail if the buffer is too small
+            Compression::Zstd => out.extend_from_slice(&zstd::bulk::compress(data, 0).expect("zstd compression failed"))
+        }
+    }
+
+    /// Decompress `data` without ever allocating more than `max_size` bytes.
+    pub(crate) fn decompress(self, data: &[u8], max_size: usize) -> Result<Vec<u8>, DecompressError> {
+        let corrupt = |e: &dyn std::fmt::Display| DecompressError::Corrupt(e.to_string());
+        match self {
+            Compression::Lz4 => {
+                let (size, _) = lz4_flex::block::uncompressed_size(data).map_err(|e| corrupt(&e))?;
+                if size > max_size {
+                    return Err(DecompressError::TooLarge);
+                }
+                lz4_flex::decompress_size_prepended(data).map_err(|e| corrupt(&e))
+            },
+            Compression::Zstd => {
+                // Every frame written by `compress_into` records its size
+                let size = match zstd::zstd_safe::get_frame_content_size(data) {
+                    Ok(Some(size)) => size,
+                    Ok(None) => return Err(DecompressError::Corrupt("zstd frame does not record its size".into())),
+                    Err(_) => return Err(DecompressError::Corrupt("not a zstd frame".into()))
+                };
+                if size > max_size as u64 {
+                    return Err(DecompressError::TooLarge);
+                }
+                zstd::bulk::decompress(data, size as usize).map_err(|e| corrupt(&e))
+            }
+        }
+    }
+}
@@ -1,31 +1,44 @@
-use std::io::{BufRead, BufReader};
-use std::fs::File;
+use super::builder::Options;
+use super::config_file;
+use super::Address;
+use std::collections::HashMap;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
 
 pub type NodeId = usize;
 
 #[derive(Clone)]
 pub struct NodeConfiguration {
     pub node_id: NodeId,
-    pub hostname: String,
-    pub port: u16,
+    /// The name the node is listed under in the config file.
+    pub name: String,
+    pub address: Address,
+    /// The node's TLS certificate, if the group uses TLS.
+    pub certificate: Option<PathBuf>,
+    /// `address` as written in the config file, before Unix socket paths are
+    /// taken relative to the file's directory.
+    pub(crate) listed_address: Address
 }
 
 pub struct Config {
     configurations: Vec<NodeConfiguration>,
-    next_id: usize
+    next_id: usize,
+    pub(crate) options: Options
 }
 
 impl Config {
-    fn new() -> Self {
+    pub(crate) fn new() -> Self {
         Self {
             configurations: Vec::new(),
-            next_id: 0
+            next_id: 0,
+            options: Default::default()
         }
     }
 
-    fn add_node(&mut self, hostname: String, port: u16) -> usize {
+    fn add_node(&mut self, name: String, address: Address, listed_address: Address, certificate: Option<PathBuf>) -> usize {
         let node_id = self.next_id;
-        self.configurations.push(NodeConfiguration { node_id, hostname, port });
+        self.configurations.push(NodeConfiguration { node_id, name, address, certificate, listed_address });
         self.next_id += 1;
 
         node_id
@@ -35,6 +48,22 @@ impl Config {
         self.configurations.get(node_id)
     }
 
+    /// The id of the node listed as `name`.
+    pub fn node_id(&self, name: &str) -> Option<NodeId> {
+        self.configurations.iter().position(|node| node.name == name)
+    }
+
+    /// The name `node_id` is listed under.
+    pub fn name(&self, node_id: NodeId) -> Option<&str> {
+        self.get(node_id).map(|node| node.name.as_str())
+    }
+
+    /// The names of every node, to keep once the config is handed to
+    /// `MulticastBuilder`.
+    pub fn names(&self) -> NodeNames {
+        NodeNames { names: self.configurations.iter().map(|node| node.name.clone()).collect() }
+    }
+
     pub fn get_connection_list(node_id: usize) -> impl Iterator<Item=usize> {
         0..node_id
     }
@@ -42,52 +71,266 @@ impl Config {
     pub fn len(&self) -> usize {
         self.configurations.len()
     }
+
+    pub fn is_empty(&self) -> bool {
+        self.configurations.is_empty()
+    }
+
+    /// Whether certificates are listed for the nodes, in which case members
+    /// only talk to each other over mutually authenticated TLS.
+    pub fn uses_tls(&self) -> bool {
+        self.configurations.iter().any(|node| node.certificate.is_some())
+    }
+}
+
+/// The names of a group's nodes, by id. Cheap to clone.
+#[derive(Clone, Debug, Default)]
+pub struct NodeNames {
+    names: Arc<[String]>
+}
+
+impl NodeNames {
+    /// The id of the node named `name`.
+    pub fn node_id(&self, name: &str) -> Option<NodeId> {
+        self.names.iter().position(|n| n == name)
+    }
+
+    /// The name of `node_id`.
+    pub fn name(&self, node_id: NodeId) -> Option<&str> {
+        self.names.get(node_id).map(String::as_str)
+    }
+
+    /// The name of `node_id`, or `node <id>` if it has none.
+    pub fn describe(&self, node_id: NodeId) -> String {
+        match self.name(node_id) {
+            Some(name) => name.into(),
+            None => format!("node {}", node_id)
+        }
+    }
+
+    /// The names of `node_ids`, or their ids if they have none.
+    pub(crate) fn list(&self, node_ids: &[NodeId]) -> String {
+        let names: Vec<_> = node_ids
+            .iter()
+            .map(|&id| self.name(id).map_or_else(|| id.to_string(), String::from))
+            .collect();
+        format!("[{}]", names.join(", "))
+    }
+}
+
+/// A node as listed in a config file, along with the line it is listed on.
+pub(crate) struct NodeEntry {
+    pub line: usize,
+    pub name: String,
+    /// Unix socket paths are relative to the config file.
+    pub address: Address,
+    /// Relative to the config file.
+    pub certificate: Option<PathBuf>
 }
 
+/// A config file problem, found on `line` if it is known.
+pub(crate) struct ConfigError {
+    pub line: Option<usize>,
+    pub message: String
+}
+
+impl ConfigError {
+    pub fn at(line: usize, message: String) -> Self {
+        Self { line: Some(line), message }
+    }
+
+    pub fn general(message: String) -> Self {
+        Self { line: None, message }
+    }
+
+    fn describe(&self, path: &str) -> String {
+        match self.line {
+            Some(line) => format!("Bad config {}:{}: {}", path, line, self.message),
+            None => format!("Bad config {}: {}", path, self.message)
+        }
+    }
+}
+
+/// Parse a config file, and find the id of the node named `given_node_name`.
+///
+/// Files ending in `.toml` are read as TOML, which lists the nodes as
+/// `[[nodes]]` tables and can set options for the group (see the README).
+/// Any other file is read in the original format: the first line is the
+/// number of nodes, and every other line is
+/// `<node name> <hostname> <port> [certificate]`, or
+/// `<node name> unix:<socket path> [certificate]` for a node listening on a
+/// Unix socket. Blank lines and lines starting with `#` are skipped.
+///
+/// Certificates are PEM files, relative to the config file as are socket
+/// paths. Certificates must be listed
+/// for every node or for none of them. Errors give the line they were found on.
 pub fn parse_config(path: &str, given_node_name: &str) -> Result<(Config, NodeId), String> {
-    let mut config = Config::new();
-    let mut rdr = match File::open(path) {
-        Ok(f) => BufReader::new(f),
-        Err(e) => return Err(e.to_string())
+    let source = fs::read_to_string(path).map_err(|e| format!("Could not read config {}: {}", path, e))?;
+    let config_dir = Path::new(path).parent().unwrap_or(Path::new(""));
+
+    let parsed = match Path::new(path).extension().and_then(|ext| ext.to_str()) {
+        Some("toml") => config_file::parse_toml(&source, config_dir, given_node_name),
+        _ => parse_legacy(&source).and_then(|(count, nodes)| build_config(count, nodes, config_dir, given_node_name))
     };
-    
-    let mut buf = String::new();
-    let node_count: usize = match rdr.read_line(&mut buf) {
-        Ok(_) => match buf.trim().parse() {
-            Ok(count) => count,
-            Err(_) => return Err("Bad config: could not parse node count".into())
+    parsed.map_err(|e| e.describe(path))
+}
+
+/// Read the node count and the nodes from a config file in the original
+/// format.
+fn parse_legacy(source: &str) -> Result<((usize, usize), Vec<NodeEntry>), ConfigError> {
+    let mut lines = source
+        .lines()
+        .enumerate()
+        .map(|(i, line)| (i + 1, line.trim()))
+        .filter(|(_, line)| !line.is_empty() && !line.starts_with('#'));
+
+    let count = match lines.next() {
+        Some((line, count)) => match count.parse() {
+            Ok(count) => (line, count),
+            Err(_) => return Err(ConfigError::at(line, format!("could not parse node count {:?}", count)))
         },
-        Err(_) => return Err("Bad config: missing node count".into())
+        None => return Err(ConfigError::general("the file is empty".into()))
     };
 
-    buf.clear();
-    let mut this_node_id: Option<NodeId> = None;
-    while let Ok(n) = rdr.read_line(&mut buf) {
-        if n == 0 { break }
+    let nodes = lines
+        .map(|(line, text)| {
+            let delimited: Vec<_> = text.split_ascii_whitespace().collect();
+            let (name, address, certificate) = match delimited[..] {
+                [name, address, ref rest @ ..] if address.starts_with("unix:") && rest.len() <= 1 =>
+                    (name, Address::Unix(address["unix:".len()..].into()), rest.first()),
+                [name, hostname, port, ref rest @ ..] if rest.len() <= 1 => {
+                    let port = port.parse().map_err(|_| ConfigError::at(line, format!("could not parse port {:?}", port)))?;
+                    (name, Address::Tcp { host: hostname.into(), port }, rest.first())
+                },
+                _ => return Err(ConfigError::at(line, 
+                    "expected <node name> <hostname> <port> [certificate] or <node name> unix:<path> [certificate]".into()))
+            };
 
-        let delimited: Vec<_> = buf.split_ascii_whitespace().collect();
-        match delimited[0..3] {
-            [node_name, hostname, p] => match p.parse() {
-                Ok(port) => {
-                    let id = config.add_node(hostname.into(), port);
+            Ok(NodeEntry { line, name: name.into(), address, certificate: certificate.map(|cert| cert.into()) })
+        })
+        .collect::<Result<Vec<_>, _>>()?;
 
-                    if node_name == given_node_name {
-                        this_node_id = Some(id);
-                    }
-                },
-                Err(_) => return Err(format!("Bad config: could not parse port for node with id: {}", n))
-            },
-            _ => return Err("Bad config: too little arguments per line".into())
+    Ok((count, nodes))
+}
+
+/// Check the nodes listed in a config file and build a config from them.
+/// `count` is the number of nodes the file says it lists, and the line it
+/// says so on.
+pub(crate) fn build_config(count: (usize, usize), nodes: Vec<NodeEntry>, config_dir: &Path, given_node_name: &str)
+        -> Result<(Config, NodeId), ConfigError> {
+    let (count_line, count) = count;
+    if nodes.len() != count {
+        return Err(ConfigError::at(count_line, format!("{} nodes are expected but {} are listed", count, nodes.len())));
+    }
+
+    let mut names = HashMap::new();
+    let mut addresses = HashMap::new();
+    for node in &nodes {
+        if let Some(first) = names.insert(node.name.as_str(), node.line) {
+            return Err(ConfigError::at(node.line, format!("node {} is already listed on line {}", node.name, first)));
+        }
+        if let Some(first) = addresses.insert(&node.address, node.line) {
+            return Err(ConfigError::at(node.line, format!("{} is already used by the node on line {}", node.address, first)));
+        }
+    }
+
+    let with_certificate = nodes.iter().filter(|node| node.certificate.is_some()).count();
+    if with_certificate != 0 && with_certificate != nodes.len() {
+        let line = nodes.iter().find(|node| node.certificate.is_none()).unwrap().line;
+        return Err(ConfigError::at(line, "certificates must be listed for every node or for none".into()));
+    }
+
+    let mut config = Config::new();
+    let mut this_node_id = None;
+    for node in nodes {
+        let is_this_node = node.name == given_node_name;
+        let address = match &node.address {
+            Address::Unix(path) => Address::Unix(config_dir.join(path)),
+            address => address.clone()
+        };
+        let id = config.add_node(node.name, address, node.address, node.certificate.map(|cert| config_dir.join(cert)));
+        if is_this_node {
+            this_node_id = Some(id);
+        }
+    }
+
+    match this_node_id {
+        Some(id) => Ok((config, id)),
+        None => Err(ConfigError::general(format!("node {} is not listed", given_node_name)))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn parse(source: &str) -> Result<(Config, NodeId), ConfigError> {
+        parse_legacy(source).and_then(|(count, nodes)| build_config(count, nodes, Path::new("conf"), "b"))
+    }
+
+    fn error(source: &str) -> (Option<usize>, String) {
+        match parse(source) {
+            Ok(_) => panic!("config was accepted"),
+            Err(e) => (e.line, e.message)
+        }
+    }
+
+    #[test]
+    fn parses_nodes() {
+        let Ok((config, id)) = parse("# nodes\n2\n\na localhost 4000\nb unix:b.sock\n") else {
+            panic!("config was rejected");
         };
+        assert_eq!(id, 1);
+        assert_eq!(config.len(), 2);
+        assert_eq!(config.name(0), Some("a"));
+        assert_eq!(config.get(1).unwrap().address, Address::Unix("conf/b.sock".into()));
+        assert!(!config.uses_tls());
+    }
 
-        buf.clear();
+    #[test]
+    fn rejects_duplicate_name() {
+        let (line, message) = error("2\nb localhost 4000\nb localhost 4001\n");
+        assert_eq!(line, Some(3));
+        assert_eq!(message, "node b is already listed on line 2");
     }
 
-    if this_node_id.is_none() {
-        return Err(format!("Bad config: node identifier is not listed in config file"));
-    } else if config.len() != node_count {
-        return Err(format!("Bad config: expected node count ({}) does not match given count ({})", node_count, config.len()));
+    #[test]
+    fn rejects_duplicate_address() {
+        let (line, message) = error("3\na localhost 4000\nb localhost 4001\n\nc localhost 4000\n");
+        assert_eq!(line, Some(5));
+        assert!(message.ends_with("is already used by the node on line 2"), "{}", message);
     }
 
-    Ok((config, this_node_id.unwrap()))
-}
\ No newline at end of file
+    #[test]
+    fn rejects_count_mismatch() {
+        let (line, message) = error("\n3\na localhost 4000\nb localhost 4001\n");
+        assert_eq!(line, Some(2));
+        assert_eq!(message, "3 nodes are expected but 2 are listed");
+    }
+
+    #[test]
+    fn rejects_short_line() {
+        let (line, _) = error("2\na localhost 4000\nb localhost\n");
+        assert_eq!(line, Some(3));
+    }
+
+    #[test]
+    fn rejects_bad_count_and_port() {
+        assert_eq!(error("two\n").0, Some(1));
+        assert_eq!(error("1\nb localhost port\n"), (Some(2), "could not parse port \"port\"".into()));
+        assert_eq!(error("").0, None);
+    }
+
+    #[test]
+    fn requires_certificates_for_every_node() {
+        let (line, _) = error("2\na localhost 4000 a.pem\nb localhost 4001\n");
+        assert_eq!(line, Some(3));
+    }
+
+    #[test]
+    fn requires_given_node() {
+        let parsed = parse_legacy("1\na localhost 4000\n")
+            .and_then(|(count, nodes)| build_config(count, nodes, Path::new(""), "z"));
+        assert!(matches!(parsed, Err(ConfigError { line: None, ref message }) if message == "node z is not listed"));
+    }
+}
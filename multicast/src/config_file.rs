@@ -0,0 +1,300 @@
+use super::{
+    Config, NodeId, Address, OverflowPolicy, WireFormat, Compression, CompressionConfig, BatchConfig, RetryStrategy,
+    QuorumConfig, Dissemination,
+    config::{NodeEntry, ConfigError, build_config}
+};
+use serde::Deserialize;
+use toml::Spanned;
+use std::{net::IpAddr, path::{Path, PathBuf}, time::Duration};
+
+/// A config file in TOML, which lists every node as a `[[nodes]]` table and
+/// may set options for the whole group in an `[options]` table.
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct ConfigFile {
+    /// If given, the number of nodes that must be listed.
+    count: Option<Spanned<usize>>,
+    #[serde(default)]
+    options: GroupOptions,
+    nodes: Vec<Spanned<NodeTable>>
+}
+
+/// The options a config file may set. Any of them can still be overridden
+/// through `MulticastBuilder`.
+#[derive(Deserialize, Default)]
+#[serde(deny_unknown_fields)]
+struct GroupOptions {
+    cluster_name: Option<String>,
+    wire_format: Option<WireFormat>,
+    connect_timeout_ms: Option<u64>,
+    channel_capacity: Option<usize>,
+    overflow_policy: Option<OverflowPolicy>,
+    max_message_size: Option<usize>,
+    max_queued_bytes: Option<usize>,
+    length_field_length: Option<Spanned<usize>>,
+    reconnect_window_ms: Option<u64>,
+    retransmit_buffer: Option<usize>,
+    compression: Option<CompressionTable>,
+    batching: Option<BatchingTable>,
+    retry: Option<RetryTable>,
+    quorum: Option<Spanned<QuorumTable>>,
+    dissemination: Option<DisseminationTable>
+}
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct CompressionTable {
+    algorithm: Option<Compression>,
+    threshold: Option<usize>
+}
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct BatchingTable {
+    max_bytes: Option<usize>,
+    max_delay_ms: Option<u64>
+}
+
+#[derive(Deserialize, Clone, Copy)]
+#[serde(rename_all = "lowercase")]
+enum RetryKind {
+    Fixed,
+    Exponential,
+    Jitter
+}
+
+/// How members are dialed. `initial_ms` is the wait between attempts for the
+/// fixed strategy.
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct RetryTable {
+    strategy: Option<RetryKind>,
+    initial_ms: Option<u64>,
+    max_ms: Option<u64>,
+    max_attempts: Option<usize>,
+    deadline_ms: Option<u64>
+}
+
+#[derive(Deserialize, Clone, Copy)]
+#[serde(rename_all = "lowercase")]
+enum DisseminationKind {
+    Flooding,
+    Gossip,
+    Tree,
+    Lazy
+}
+
+/// How messages are passed on. `fanout` is only used by gossip and the tree.
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct DisseminationTable {
+    strategy: DisseminationKind,
+    fanout: Option<usize>
+}
+
+/// Start once `size` members are connected, waiting `wait_ms` for the rest.
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct QuorumTable {
+    size: usize,
+    wait_ms: Option<u64>
+}
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct NodeTable {
+    name: String,
+    host: Option<String>,
+    port: Option<u16>,
+    /// The node listens on a Unix socket at this path instead of on a port.
+    unix: Option<PathBuf>,
+    /// The interface the node listens on, if not every interface.
+    bind: Option<IpAddr>,
+    certificate: Option<PathBuf>,
+    /// The node's TLS private key. Only read by the node itself.
+    key: Option<PathBuf>
+}
+
+/// The line of `source` that the byte at `offset` is on.
+fn line_of(source: &str, offset: usize) -> usize {
+    source[..offset.min(source.len())].matches('\n').count() + 1
+}
+
+/// Parse a TOML config file, and find the id of the node named
+/// `given_node_name`.
+pub(crate) fn parse_toml(source: &str, config_dir: &Path, given_node_name: &str) -> Result<(Config, NodeId), ConfigError> {
+    let file: ConfigFile = toml::from_str(source).map_err(|e| {
+        let message = e.message().to_string();
+        match e.span() {
+            Some(span) => ConfigError::at(line_of(source, span.start), message),
+            None => ConfigError::general(message)
+        }
+    })?;
+
+    let mut bind = None;
+    let mut key = None;
+    let mut entries = Vec::with_capacity(file.nodes.len());
+    for node in file.nodes {
+        let line = line_of(source, node.span().start);
+        let node = node.into_inner();
+        let address = match (node.host, node.port, node.unix) {
+            (Some(host), Some(port), None) => Address::Tcp { host, port },
+            (None, None, Some(path)) => Address::Unix(path),
+            _ => return Err(ConfigError::at(line, format!("node {} must have either a host and a port or a unix socket", node.name)))
+        };
+        if node.key.is_some() && node.certificate.is_none() {
+            return Err(ConfigError::at(line, format!("node {} has a key but no certificate", node.name)));
+        }
+        if node.name == given_node_name {
+            bind = node.bind;
+            key = node.key;
+        }
+        entries.push(NodeEntry { line, name: node.name, address, certificate: node.certificate });
+    }
+
+    let count = match file.count {
+        Some(count) => (line_of(source, count.span().start), count.into_inner()),
+        None => (1, entries.len())
+    };
+    let (mut config, node_id) = build_config(count, entries, config_dir, given_node_name)?;
+
+    config.options.bind_ip = bind;
+    config.options.tls_private_key = key.map(|key| config_dir.join(key));
+
+    let group = file.options;
+    if let Some(table) = group.quorum {
+        let line = line_of(source, table.span().start);
+        let table = table.into_inner();
+        let quorum = QuorumConfig { size: table.size, wait: Duration::from_millis(table.wait_ms.unwrap_or(5000)) };
+        quorum.check(config.len()).map_err(|message| ConfigError::at(line, message))?;
+        config.options.quorum = Some(quorum);
+    }
+    let options = &mut config.options;
+    if let Some(length) = group.length_field_length {
+        if !(1..=8).contains(length.get_ref()) {
+            return Err(ConfigError::at(line_of(source, length.span().start), "length_field_length must be between 1 and 8".into()));
+        }
+        options.length_field_length = length.into_inner();
+    }
+    if let Some(name) = group.cluster_name {
+        options.cluster_name = name;
+    }
+    if let Some(format) = group.wire_format {
+        options.wire_format = format;
+    }
+    if let Some(timeout) = group.connect_timeout_ms {
+        options.connect_timeout = Duration::from_millis(timeout);
+    }
+    if let Some(policy) = group.overflow_policy {
+        options.overflow_policy = policy;
+    }
+    if let Some(size) = group.max_message_size {
+        options.max_message_size = size;
+    }
+    if let Some(bytes) = group.max_queued_bytes {
+        options.max_queued_bytes = bytes;
+    }
+    if let Some(window) = group.reconnect_window_ms {
+        options.reconnect_window = Some(Duration::from_millis(window));
+    }
+    if let Some(messages) = group.retransmit_buffer {
+        options.retransmit_buffer = messages.max(1);
+    }
+    options.channel_capacity = group.channel_capacity;
+    options.compression = group.compression.map(|table| {
+        let default = CompressionConfig::default();
+        CompressionConfig {
+            algorithm: table.algorithm.unwrap_or(default.algorithm),
+            threshold: table.threshold.unwrap_or(default.threshold)
+        }
+    });
+    if let Some(table) = group.retry {
+        let initial = Duration::from_millis(table.initial_ms.unwrap_or(100));
+        let max = Duration::from_millis(table.max_ms.unwrap_or(5000));
+        options.retry_strategy = match table.strategy.unwrap_or(RetryKind::Fixed) {
+            RetryKind::Fixed => RetryStrategy::Fixed(initial),
+            RetryKind::Exponential => RetryStrategy::Exponential { initial, max },
+            RetryKind::Jitter => RetryStrategy::ExponentialJitter { initial, max }
+        };
+        options.max_connect_attempts = table.max_attempts.map(|attempts| attempts.max(1));
+        options.peer_connect_deadline = table.deadline_ms.map(Duration::from_millis);
+    }
+    if let Some(table) = group.dissemination {
+        let fanout = table.fanout.unwrap_or(2).max(1);
+        options.dissemination = match table.strategy {
+            DisseminationKind::Flooding => Dissemination::Flooding,
+            DisseminationKind::Gossip => Dissemination::Gossip { fanout },
+            DisseminationKind::Tree => Dissemination::Tree { fanout },
+            DisseminationKind::Lazy => Dissemination::Lazy
+        };
+    }
+    options.batching = group.batching.map(|table| {
+        let default = BatchConfig::default();
+        BatchConfig {
+            max_bytes: table.max_bytes.unwrap_or(default.max_bytes),
+            max_delay: table.max_delay_ms.map(Duration::from_millis).unwrap_or(default.max_delay)
+        }
+    });
+
+    Ok((config, node_id))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Three nodes, of which `b` listens on a Unix socket, after `header`.
+    fn source(header: &str) -> String {
+        let nodes = [("a", "host = \"localhost\"\nport = 4000"), ("b", "unix = \"b.sock\""), ("c", "host = \"localhost\"\nport = 4002")]
+            .iter()
+            .map(|(name, address)| format!("\n[[nodes]]\nname = \"{}\"\n{}\n", name, address))
+            .collect::<String>();
+        format!("{}{}", header, nodes)
+    }
+
+    #[test]
+    fn reads_nodes_and_options() {
+        let header = "[options]\nlength_field_length = 2\nquorum = { size = 2 }\ndissemination = { strategy = \"tree\", fanout = 3 }\n";
+        let Ok((config, node_id)) = parse_toml(&source(header), Path::new("conf"), "b") else {
+            panic!("config was rejected");
+        };
+
+        assert_eq!(node_id, 1);
+        assert_eq!(config.len(), 3);
+        assert_eq!(config.get(1).unwrap().address, Address::Unix("conf/b.sock".into()));
+        assert_eq!(config.options.length_field_length, 2);
+        assert_eq!(config.options.quorum.map(|quorum| quorum.size), Some(2));
+        assert_eq!(config.options.dissemination, Dissemination::Tree { fanout: 3 });
+    }
+
+    #[test]
+    fn rejects_out_of_range_options_on_their_line() {
+        let cases = [
+            ("[options]\nlength_field_length = 0\n", 2),
+            ("[options]\ncluster_name = \"x\"\nlength_field_length = 9\n", 3),
+            // A quorum must be a majority of the three nodes
+            ("[options]\nquorum = { size = 1 }\n", 2),
+            ("[options]\n\nquorum = { size = 4, wait_ms = 10 }\n", 3),
+            ("count = 2\n", 1),
+            ("[[nodes]]\nname = \"d\"\nhost = \"localhost\"\n", 1)
+        ];
+        for (header, line) in cases {
+            match parse_toml(&source(header), Path::new(""), "a") {
+                Ok(_) => panic!("accepted {:?}", header),
+                Err(e) => assert_eq!(e.line, Some(line), "{:?}: {}", header, e.message)
+            }
+        }
+    }
+
+    #[test]
+    fn accepts_every_length_field_length_and_majority() {
+        for length in 1..=8 {
+            let header = format!("[options]\nlength_field_length = {}\n", length);
+            assert!(parse_toml(&source(&header), Path::new(""), "a").is_ok(), "length {} was rejected", length);
+        }
+        for size in 2..=3 {
+            let header = format!("[options]\nquorum = {{ size = {} }}\n", size);
+            assert!(parse_toml(&source(&header), Path::new(""), "a").is_ok(), "quorum {} was rejected", size);
+        }
+    }
+}
@@ -1,158 +1,420 @@
 use super::{
-    member::{member_loop, MulticastMemberData}, Config, NodeId,
-    MulticastMemberHandle, MemberStateMessage, MulticastGroup
+    Config, NodeId, MulticastMemberHandle, MemberStateMessage, MulticastGroup, ConnectError, IncomingChannel,
+    Address, builder::Options, channel::{self, Sender, FlowControl}, tls::{Tls, PeerStream}, handshake::Handshake,
+    stats::StatsRegistry, reconnect::{Reconnect, Reconnections}, transport::{Stream, Listener}, retry::RetryPolicy
 };
 use tokio::{
     sync::mpsc::{UnboundedReceiver, UnboundedSender, unbounded_channel},
-    io::{AsyncWriteExt, AsyncBufReadExt, BufStream}, time::timeout,
-    net::{TcpStream, TcpListener}, select, 
+    time::{self, timeout, Instant}, task::{JoinHandle, JoinSet}, select,
 };
-use tokio_retry::{Retry, strategy::FixedInterval};
 use serde::{Serialize, de::DeserializeOwned};
-use std::{net::SocketAddr, time::Duration};
+use std::{time::Duration, io, fmt, sync::Arc};
 use log::{trace, error};
 
 pub(super) struct ConnectionPool<M> {
     pub group: MulticastGroup,
     pub node_id: NodeId,
-    timeout_secs: Option<u64>,
-    pub from_members: UnboundedReceiver<MemberStateMessage<M>>,
-    pub client_snd_handle: UnboundedSender<MemberStateMessage<M>>
+    listener: Option<Listener>,
+    pub from_members: IncomingChannel<M>,
+    pub client_snd_handle: Sender<MemberStateMessage<M>>,
+    pub flow: FlowControl,
+    pub stats: StatsRegistry,
+    reconnections: Reconnections,
+    tls: Option<Arc<Tls>>
 }
 
-static CONNECTION_POOL_INIT_TIMEOUT_SECS: u64 = 60;
-static CONNECTION_RETRY_DELAY_MS: u64 = 100;
+/// The handle to the task that accepts connections from nodes joining a group
+/// that is already running, and from members reconnecting to this node.
+pub(super) struct JoinListener {
+    joins: UnboundedReceiver<(PeerStream, Handshake)>,
+    reconnections: Reconnections,
+    handle: JoinHandle<()>
+}
+
+impl JoinListener {
+    /// Where handlers for members that joined wait for them to reconnect.
+    pub fn reconnections(&self) -> &Reconnections {
+        &self.reconnections
+    }
+
+    /// The next joining node's connection and the handshake it sent.
+    pub async fn recv(&mut self) -> Option<(PeerStream, Handshake)> {
+        self.joins.recv().await
+    }
+}
+
+impl Drop for JoinListener {
+    fn drop(&mut self) {
+        self.handle.abort()
+    }
+}
+
+/// The number of times a joining node attempts to reach each configured node
+/// before assuming that node is not part of the running group.
+static JOIN_CONNECT_ATTEMPTS: usize = 10;
+
+/// How long a peer has to complete its handshake before it is turned away.
+static HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Why dialing a member failed.
+#[derive(Debug)]
+pub(crate) enum DialError {
+    /// The member could not be reached, or dropped the connection.
+    Unreachable(io::Error),
+    /// The member answered with a handshake that could not be read, that does
+    /// not match this node's, or that came from another node.
+    Malformed(String),
+    /// The member's group has already started without this node.
+    AlreadyStarted
+}
+
+impl fmt::Display for DialError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            DialError::Unreachable(error) => write!(f, "{error}"),
+            DialError::Malformed(reason) => write!(f, "bad handshake: {reason}"),
+            DialError::AlreadyStarted => write!(f, "the group has already started without this node")
+        }
+    }
+}
+
+/// Set up a connection from a peer: open TLS if the group uses it, read the
+/// peer's handshake, check that it is a member of this group that `expected`
+/// accepts, and answer with this node's handshake. Returns the peer's
+/// handshake, or why the peer was turned away if it was.
+async fn accept_peer<F>(stream: Stream, ours: &Handshake, node_count: usize, tls: Option<&Tls>, expected: F) 
+        -> Result<(PeerStream, Handshake), String> 
+        where F: Fn(NodeId) -> bool {
+    let handshake = async {
+        let mut stream = match tls {
+            Some(tls) => tls.accept(stream).await.map_err(|e| format!("TLS failed: {}", e))?,
+            None => PeerStream::Plain(stream)
+        };
+
+        let theirs = Handshake::read(&mut stream).await.map_err(|e| format!("malformed handshake: {}", e))?;
+        ours.check(&theirs)?;
+
+        let id = theirs.node_id;
+        if id == ours.node_id || id >= node_count || !expected(id) {
+            return Err(format!("node {} is not expected to connect", id));
+        } else if tls.is_some_and(|tls| !tls.authenticate(&stream, id)) {
+            return Err(format!("it did not present the certificate of node {}", id));
+        }
+
+        ours.write(&mut stream).await.map_err(|e| format!("could not answer handshake: {}", e))?;
+        Ok((stream, theirs))
+    };
+
+    match timeout(HANDSHAKE_TIMEOUT, handshake).await {
+        Ok(result) => result,
+        Err(_) => Err("handshake timed out".into())
+    }
+}
+
+/// Accept connections from joining nodes, if `joins` is given, and pass those
+/// from reconnecting members to their handlers.
+async fn accept_loop(listener: Listener, handshake: Handshake, node_count: usize, tls: Option<Arc<Tls>>, 
+        joins: Option<UnboundedSender<(PeerStream, Handshake)>>, reconnections: Reconnections) {
+    loop {
+        let (stream, addr) = match listener.accept().await {
+            Ok(client) => client,
+            Err(e) => {
+                error!("Could not accept client: {:?}", e);
+                continue
+            }
+        };
+
+        match accept_peer(stream, &handshake, node_count, tls.as_deref(), |_| true).await {
+            Ok((stream, theirs)) if theirs.resuming => {
+                trace!("Node {} at {} is reconnecting", theirs.node_id, addr);
+                if let Err(reason) = reconnections.resume(theirs.node_id, stream) {
+                    error!("Rejecting connection from {}: {}", addr, reason)
+                }
+            },
+            Ok((_, theirs)) if !theirs.joining => 
+                error!("Rejecting connection from {}: node {} is starting up, but the group has already started", addr, theirs.node_id),
+            Ok((stream, theirs)) => match joins.as_ref() {
+                Some(joins) => {
+                    trace!("Node {} at {} is asking to join", theirs.node_id, addr);
+                    if joins.send((stream, theirs)).is_err() {
+                        break
+                    }
+                },
+                None => error!("Rejecting connection from {}: the group does not accept nodes joining later", addr)
+            },
+            Err(reason) => error!("Rejecting connection from {}: {}", addr, reason)
+        }
+    }
+}
+
+/// Dial `node_id` at `address`, retrying as `retry` says, and exchange
+/// handshakes with it, returning its handshake.
+pub(crate) async fn connect_to_node(ours: Handshake, node_id: NodeId, address: Address, retry: RetryPolicy, 
+        tls: Option<Arc<Tls>>) -> Result<(PeerStream, Handshake), DialError> {
+    trace!("Connecting to {} at {}...", node_id, address);
+    let stream = retry.dial(node_id, &address).await.map_err(DialError::Unreachable)?;
+
+    let mut stream = match tls {
+        Some(tls) => tls.connect(stream, node_id, &address).await.map_err(DialError::Unreachable)?,
+        None => PeerStream::Plain(stream)
+    };
+
+    ours.write(&mut stream).await.map_err(DialError::Unreachable)?;
+    let theirs = match timeout(HANDSHAKE_TIMEOUT, Handshake::read(&mut stream)).await {
+        Ok(Ok(theirs)) => theirs,
+        Ok(Err(e)) if e.kind() == io::ErrorKind::UnexpectedEof => return Err(DialError::Unreachable(
+            io::Error::new(e.kind(), "the node turned this node away, see its log for why"))),
+        Ok(Err(e)) if e.kind() == io::ErrorKind::InvalidData => return Err(DialError::Malformed(e.to_string())),
+        Ok(Err(e)) => return Err(DialError::Unreachable(e)),
+        Err(_) => return Err(DialError::Unreachable(io::Error::new(io::ErrorKind::TimedOut, "handshake timed out")))
+    };
+
+    ours.check(&theirs).map_err(DialError::Malformed)?;
+    if theirs.node_id != node_id {
+        return Err(DialError::Malformed(format!("node {} answered in its place", theirs.node_id)));
+    } else if theirs.started && !ours.joining && !ours.resuming {
+        return Err(DialError::AlreadyStarted);
+    }
+
+    Ok((stream, theirs))
+}
 
 impl<M> ConnectionPool<M> {
-    pub(super) fn new(node_id: NodeId) -> Self {
-        let (client_snd_handle, from_clients) = unbounded_channel();
+    pub(super) fn new(node_id: NodeId, options: &Options) -> Self {
+        let (client_snd_handle, from_clients) = channel::channel(options.channel_capacity);
 
         Self {
             group: Default::default(),
             node_id,
-            timeout_secs: None,
+            listener: None,
             from_members: from_clients,
-            client_snd_handle
+            client_snd_handle,
+            flow: FlowControl::new(options.channel_capacity, options.overflow_policy),
+            stats: Default::default(),
+            reconnections: Default::default(),
+            tls: None
         }
     }
 
-    pub(super) fn with_timeout(mut self, secs: u64) -> Self {
-        self.timeout_secs = Some(secs);
-        self
+    /// The nodes listed in `config` that have not been admitted to the group.
+    fn missing_members(&self, config: &Config) -> Vec<NodeId> {
+        (0..config.len())
+            .filter(|id| *id != self.node_id && !self.group.contains_key(id))
+            .collect()
     }
 
-    async fn connect_to_node(this_node: NodeId, node_id: NodeId, host: String, port: u16, stream_snd: UnboundedSender<(TcpStream, NodeId)>) {
-        let server_addr = format!("{host}:{port}");
-        trace!("Connecting to {} at {}...", node_id, server_addr);
-
-        let retry_strategy = FixedInterval::from_millis(CONNECTION_RETRY_DELAY_MS);
-        match Retry::spawn(retry_strategy, || TcpStream::connect(&server_addr)).await {
-            Ok(mut stream) => {
-                trace!("Connected to {} at {}", node_id, server_addr);
+    fn validate_options(&self, config: &Config) -> Result<(), ConnectError> {
+        config.options.validate(config.len())
+            .map_err(|reason| ConnectError::InvalidOptions { reason, missing: self.missing_members(config) })
+    }
 
-                stream.write_all(format!("{}\n", this_node).as_bytes()).await.unwrap();
-                stream.flush().await.unwrap();
+    /// Load the certificates and private key if the group uses TLS.
+    fn load_tls(&mut self, config: &Config) -> Result<(), ConnectError> {
+        self.tls = Tls::from_config(config, self.node_id)
+            .map_err(|error| ConnectError::TlsSetupFailed { error, missing: self.missing_members(config) })?
+            .map(Arc::new);
+        Ok(())
+    }
 
-                stream_snd.send((stream, node_id)).unwrap();
+    /// Start a handler for the member that sent `theirs`. If this node
+    /// `dialed` the member, it also dials again to reconnect.
+    fn admit_member(&mut self, socket: PeerStream, theirs: &Handshake, dialed: bool, config: &Config) 
+            where M: 'static + Send + Serialize + DeserializeOwned {
+        let reconnect = match dialed {
+            true => {
+                let node = config.get(theirs.node_id).unwrap();
+                let ours = Handshake::new(self.node_id, config);
+                Reconnect::dial(theirs, &config.options, &ours, node.address.clone(), self.tls.clone())
             },
-            Err(e) => {
-                eprintln!("Failed to connect to {}: {:?}... Stopping.", server_addr, e);
-                std::process::exit(1);
-            }
-        }
+            false => Reconnect::wait_for(theirs, &config.options, &self.reconnections)
+        };
+        let handle = MulticastMemberHandle::spawn(socket, theirs, self.client_snd_handle.clone(), &config.options, 
+            self.flow.drained.clone(), self.stats.register(theirs.node_id), reconnect);
+        self.group.insert(theirs.node_id, handle);
     }
 
-    fn admit_member(&mut self, socket: TcpStream, member_id: NodeId) where M: 'static + Send + Serialize + DeserializeOwned {
-        let (to_client, from_engine) = unbounded_channel();
-        let member_data = MulticastMemberData {
-            member_id: member_id,
-            to_engine: self.client_snd_handle.clone(),
-            from_engine: from_engine
-        };
+    async fn bind(&self, config: &Config) -> Result<Listener, ConnectError> {
+        let address = &config.get(self.node_id).unwrap().address;
 
-        let handle = tokio::spawn(member_loop(socket, member_data));
-        self.group.insert(member_id, MulticastMemberHandle { 
-            member_id,
-            to_client,
-            handle
-        });
+        Listener::bind(address, config.options.bind_ip).await.map_err(|error| ConnectError::BindFailed {
+            addr: address.clone(),
+            error,
+            missing: self.missing_members(config)
+        })
     }
 
-    async fn priv_connect(mut self, config: &Config) -> Self where M: 'static + Send + Serialize + DeserializeOwned {
-        let node_config = config.get(self.node_id).unwrap();
-
-        let bind_addr: SocketAddr = ([0, 0, 0, 0], node_config.port).into();
-        let tcp_listener = match TcpListener::bind(bind_addr).await {
-            Ok(l) => l,
-            Err(e) => {
-                eprintln!("Failed to bind to {}: {:?}", bind_addr, e);
-                std::process::exit(1);
-            }
-        };
+    async fn priv_connect(&mut self, config: &Config) -> Result<(), ConnectError> where M: 'static + Send + Serialize + DeserializeOwned {
+        self.validate_options(config)?;
+        self.load_tls(config)?;
+        let listener = self.bind(config).await?;
+        let handshake = Handshake::new(self.node_id, config);
+        let quorum = config.options.quorum;
 
+        // Dropping the set stops dialing members that are left out of the group
+        let mut dials = JoinSet::new();
         let (stream_snd, mut stream_rcv) = unbounded_channel();
         for node in Config::get_connection_list(self.node_id) {
             let connect_config = config.get(node).cloned().unwrap();
             let snd_clone = stream_snd.clone();
-            let this_node = self.node_id;
-            tokio::spawn(async move {
-                ConnectionPool::<M>::connect_to_node(
-                    this_node, 
-                    node, 
-                    connect_config.hostname, 
-                    connect_config.port, 
-                    snd_clone
-                ).await
+            let handshake = handshake.clone();
+            let retry = RetryPolicy::new(&config.options);
+            let tls = self.tls.clone();
+            dials.spawn(async move {
+                let result = connect_to_node(
+                    handshake,
+                    node,
+                    connect_config.address,
+                    retry,
+                    tls
+                ).await;
+                let _ = snd_clone.send((node, result));
             });
         }
         drop(stream_snd);
-        
-        let out = loop {
+
+        let mut quorum_deadline = None;
+        let mut unreachable = 0;
+        while self.group.len() < config.len() - 1 {
+            if let Some(quorum) = quorum.filter(|quorum| quorum_deadline.is_none() && self.group.len() + 1 >= quorum.size) {
+                trace!("Reached a quorum of {} members, waiting {:?} for the rest", quorum.size, quorum.wait);
+                quorum_deadline = Some(Instant::now() + quorum.wait);
+            }
+
             select! {
-                client = tcp_listener.accept() => match client {
-                    Ok((stream, _addr)) => {
-                        let mut stream = BufStream::new(stream);
-                        let mut member_id = String::new();
-
-                        match stream.read_line(&mut member_id).await {
-                            Ok(0) | Err(_) => continue,
-                            Ok(_) => {
-                                let member_id: NodeId = member_id
-                                    .trim()
-                                    .parse()
-                                    .unwrap();
-                                self.admit_member(stream.into_inner(), member_id)
-                            }
+                client = listener.accept() => match client {
+                    Ok((stream, addr)) => {
+                        let group = &self.group;
+                        match accept_peer(stream, &handshake, config.len(), self.tls.as_deref(), |id| !group.contains_key(&id)).await {
+                            Ok((stream, theirs)) => self.admit_member(stream, &theirs, false, config),
+                            Err(reason) => error!("Rejecting connection from {}: {}", addr, reason)
                         }
-
-                        if self.group.len() == config.len() - 1 { break self; }
                     },
                     Err(e) => {
                         error!("Could not accept client: {:?}", e);
                         continue
                     }
                 },
-                Some((stream, member_id)) = stream_rcv.recv() => {
-                    self.admit_member(stream, member_id);
-                    if self.group.len() == config.len() - 1 { break self; }
+                Some((member_id, result)) = stream_rcv.recv() => match result {
+                    Ok((stream, theirs)) => self.admit_member(stream, &theirs, true, config),
+                    Err(DialError::AlreadyStarted) => 
+                        return Err(ConnectError::AlreadyStarted { missing: self.missing_members(config) }),
+                    Err(DialError::Malformed(reason)) => return Err(ConnectError::HandshakeMalformed {
+                        node_id: member_id,
+                        reason,
+                        missing: self.missing_members(config)
+                    }),
+                    // The group can still start without this member, as long as
+                    // enough of the others are left
+                    Err(DialError::Unreachable(error)) if quorum.is_some_and(|quorum| config.len() - unreachable > quorum.size) => {
+                        error!("Could not connect to node {}: {}", member_id, error);
+                        unreachable += 1;
+                    },
+                    Err(DialError::Unreachable(error)) => return Err(ConnectError::PeerUnreachable {
+                        node_id: member_id,
+                        error,
+                        missing: self.missing_members(config)
+                    })
+                },
+                _ = time::sleep_until(quorum_deadline.unwrap_or_else(Instant::now)), if quorum_deadline.is_some() => {
+                    error!("Starting without nodes {}, which did not connect in time", config.names().list(&self.missing_members(config)));
+                    break
                 }
             }
-        };
+        }
 
-        out
+        self.listener = Some(listener);
+        Ok(())
     }
 
-    pub(super) async fn connect(self, config: &Config) -> Self where M: 'static + Send + Serialize + DeserializeOwned {
-        let time_limit = match self.timeout_secs {
-            Some(s) => Duration::from_secs(s),
-            None => Duration::from_secs(CONNECTION_POOL_INIT_TIMEOUT_SECS)
-        };
+    pub(super) async fn connect(mut self, config: &Config) -> Result<Self, ConnectError> where M: 'static + Send + Serialize + DeserializeOwned {
+        let time_limit = config.options.connect_timeout;
         match timeout(time_limit, self.priv_connect(config)).await {
-            Ok(p) => p,
-            Err(_) => {
-                eprintln!("Failed to connect to all nodes within {}s... Stopping.", CONNECTION_POOL_INIT_TIMEOUT_SECS);
-                std::process::exit(1);
+            Ok(Ok(())) => Ok(self),
+            Ok(Err(e)) => Err(e),
+            Err(_) => Err(ConnectError::TimedOut {
+                secs: time_limit.as_secs(),
+                missing: self.missing_members(config)
+            })
+        }
+    }
+
+    async fn priv_join(&mut self, config: &Config) -> Result<(), ConnectError> where M: 'static + Send + Serialize + DeserializeOwned {
+        self.validate_options(config)?;
+        self.load_tls(config)?;
+        let listener = self.bind(config).await?;
+        let handshake = Handshake { joining: true, ..Handshake::new(self.node_id, config) };
+
+        let (stream_snd, mut stream_rcv) = unbounded_channel();
+        for node in (0..config.len()).filter(|id| *id != self.node_id) {
+            let connect_config = config.get(node).cloned().unwrap();
+            let snd_clone = stream_snd.clone();
+            let handshake = handshake.clone();
+            let retry = RetryPolicy::new(&config.options).at_most(JOIN_CONNECT_ATTEMPTS);
+            let tls = self.tls.clone();
+            tokio::spawn(async move {
+                let result = connect_to_node(
+                    handshake,
+                    node,
+                    connect_config.address,
+                    retry,
+                    tls
+                ).await;
+                let _ = snd_clone.send((node, result));
+            });
+        }
+        drop(stream_snd);
+
+        while let Some((member_id, result)) = stream_rcv.recv().await {
+            match result {
+                Ok((stream, theirs)) => self.admit_member(stream, &theirs, true, config),
+                Err(e) => trace!("Node {} is not part of the running group: {}", member_id, e)
             }
         }
+
+        if self.group.is_empty() {
+            return Err(ConnectError::GroupUnreachable { missing: self.missing_members(config) });
+        }
+
+        self.listener = Some(listener);
+        Ok(())
     }
-}
\ No newline at end of file
+
+    /// Connect to every reachable member of a group that is already running.
+    /// Members that cannot be reached are assumed to have failed.
+    pub(super) async fn join(mut self, config: &Config) -> Result<Self, ConnectError> where M: 'static + Send + Serialize + DeserializeOwned {
+        let time_limit = config.options.connect_timeout;
+        match timeout(time_limit, self.priv_join(config)).await {
+            Ok(Ok(())) => Ok(self),
+            Ok(Err(e)) => Err(e),
+            Err(_) => Err(ConnectError::TimedOut {
+                secs: time_limit.as_secs(),
+                missing: self.missing_members(config)
+            })
+        }
+    }
+
+    /// Keep accepting connections from nodes that join after the group has
+    /// been established. Must be called after `connect` or `join`.
+    pub(super) fn listen_for_joins(&mut self, config: &Config) -> JoinListener {
+        let (joins_snd, joins) = unbounded_channel();
+        let handle = self.listen(config, Some(joins_snd));
+
+        JoinListener { joins, reconnections: self.reconnections.clone(), handle }
+    }
+
+    /// Keep accepting connections from members that reconnect to this node,
+    /// if reconnection is enabled. Must be called after `connect`.
+    pub(super) fn listen_for_reconnections(&mut self, config: &Config) -> Option<JoinListener> {
+        config.options.reconnect_window?;
+        let (_, joins) = unbounded_channel();
+        let handle = self.listen(config, None);
+
+        Some(JoinListener { joins, reconnections: self.reconnections.clone(), handle })
+    }
+
+    fn listen(&mut self, config: &Config, joins: Option<UnboundedSender<(PeerStream, Handshake)>>) -> JoinHandle<()> {
+        let listener = self.listener.take().expect("connection pool is not connected");
+        let handshake = Handshake { started: true, ..Handshake::new(self.node_id, config) };
+        tokio::spawn(accept_loop(listener, handshake, config.len(), self.tls.clone(), joins, self.reconnections.clone()))
+    }
+}
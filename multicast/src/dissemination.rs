@@ -0,0 +1,141 @@
+use super::NodeId;
+use rand::seq::IteratorRandom;
+use serde::{Serialize, Deserialize};
+use std::collections::HashSet;
+
+/// How a reliably multicast message spreads through the group.
+///
+/// Whatever the strategy, members tell each other how far they have got with
+/// every sender whenever a member fails, and ask for any messages they turn
+/// out to have missed. A message delivered by one surviving member is
+/// therefore delivered by all of them, even if the members that were meant to
+/// pass it on crashed first.
+///
+/// Every member must use the same strategy, which members check during the
+/// handshake.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub enum Dissemination {
+    /// Every member passes every new message on to every other member. The
+    /// most robust, at the cost of about N² frames per message.
+    #[default]
+    Flooding,
+    /// The sender sends a message to every member, and each member passes it
+    /// on to `fanout` members picked at random. About N × `fanout` frames per
+    /// message.
+    Gossip { fanout: usize },
+    /// The message travels down a tree rooted at its sender in which every
+    /// member has up to `fanout` children. N - 1 frames per message, but more
+    /// hops. The tree is rebuilt from the remaining members when one fails.
+    Tree { fanout: usize },
+    /// The sender sends a message to every member, and members only pass it
+    /// on if the sender fails before every member acknowledged it. About N
+    /// frames per message while no member fails.
+    Lazy
+}
+
+impl Dissemination {
+    /// The members `node_id` sends a message from `origin` to, having received
+    /// it from `sender`. `members` are the other members of the group.
+    pub(crate) fn recipients(&self, node_id: NodeId, origin: NodeId, sender: NodeId, members: &HashSet<NodeId>) -> Vec<NodeId> {
+        let candidates = members.iter().cloned().filter(|member| *member != origin && *member != sender);
+        match *self {
+            Dissemination::Flooding => candidates.collect(),
+            Dissemination::Gossip { .. } | Dissemination::Lazy if node_id == origin => candidates.collect(),
+            Dissemination::Lazy => Vec::new(),
+            Dissemination::Gossip { fanout } => candidates.choose_multiple(&mut rand::thread_rng(), fanout),
+            Dissemination::Tree { fanout } => {
+                // Every member orders the group the same way, and a failed
+                // sender stays the root so its children still pass it on
+                let mut nodes: Vec<NodeId> = members.iter().cloned().chain([node_id]).collect();
+                if !nodes.contains(&origin) {
+                    nodes.push(origin);
+                }
+                nodes.sort_unstable();
+                let root = nodes.iter().position(|node| *node == origin).unwrap();
+                nodes.rotate_left(root);
+
+                let position = nodes.iter().position(|node| *node == node_id).unwrap();
+                let fanout = fanout.max(1);
+                nodes.iter()
+                    .skip(position * fanout + 1)
+                    .take(fanout)
+                    .cloned()
+                    .filter(|child| *child != sender)
+                    .collect()
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// The members of a group of `n` other than `node_id`.
+    fn others(n: usize, node_id: NodeId) -> HashSet<NodeId> {
+        (0..n).filter(|member| *member != node_id).collect()
+    }
+
+    fn children(fanout: usize, n: usize, node_id: NodeId, origin: NodeId, sender: NodeId) -> Vec<NodeId> {
+        let mut children = Dissemination::Tree { fanout }.recipients(node_id, origin, sender, &others(n, node_id));
+        children.sort_unstable();
+        children
+    }
+
+    #[test]
+    fn tree_rooted_at_first_node() {
+        assert_eq!(children(2, 7, 0, 0, 0), [1, 2]);
+        assert_eq!(children(2, 7, 1, 0, 0), [3, 4]);
+        assert_eq!(children(2, 7, 2, 0, 0), [5, 6]);
+        assert!(children(2, 7, 3, 0, 1).is_empty());
+    }
+
+    #[test]
+    fn tree_rotated_to_origin() {
+        // Ordered from the root, the group is 4 5 6 0 1 2 3
+        assert_eq!(children(2, 7, 4, 4, 4), [5, 6]);
+        assert_eq!(children(2, 7, 5, 4, 4), [0, 1]);
+        assert_eq!(children(2, 7, 6, 4, 4), [2, 3]);
+        assert!(children(2, 7, 0, 4, 5).is_empty());
+        assert!(children(2, 7, 3, 4, 6).is_empty());
+    }
+
+    #[test]
+    fn tree_reaches_every_member_once() {
+        for origin in 0..10 {
+            let mut reached: Vec<NodeId> = (0..10)
+                .flat_map(|node_id| children(3, 10, node_id, origin, origin))
+                .collect();
+            reached.sort_unstable();
+            let mut expected: Vec<NodeId> = others(10, origin).into_iter().collect();
+            expected.sort_unstable();
+            assert_eq!(reached, expected);
+        }
+    }
+
+    #[test]
+    fn tree_keeps_failed_origin_as_root() {
+        // The origin 2 failed and is no longer a member of anyone's group
+        let members = |node_id: NodeId| others(5, node_id).into_iter().filter(|member| *member != 2).collect();
+        let tree = Dissemination::Tree { fanout: 2 };
+        let mut children = tree.recipients(3, 2, 2, &members(3));
+        children.sort_unstable();
+        assert_eq!(children, [0, 1]);
+        assert!(tree.recipients(4, 2, 2, &members(4)).is_empty());
+    }
+
+    #[test]
+    fn flooding_and_gossip() {
+        assert_eq!(Dissemination::Flooding.recipients(1, 0, 2, &others(5, 1)).len(), 2);
+        let gossip = Dissemination::Gossip { fanout: 2 }.recipients(1, 0, 0, &others(6, 1));
+        assert_eq!(gossip.len(), 2);
+        assert!(gossip.iter().all(|member| ![0, 1].contains(member)));
+    }
+
+    #[test]
+    fn lazy_only_sender_sends() {
+        assert_eq!(Dissemination::Lazy.recipients(0, 0, 0, &others(5, 0)).len(), 4);
+        assert!(Dissemination::Lazy.recipients(1, 0, 0, &others(5, 1)).is_empty());
+        assert!(Dissemination::Lazy.recipients(3, 0, 2, &others(5, 3)).is_empty());
+    }
+}
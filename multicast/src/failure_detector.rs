@@ -0,0 +1,174 @@
+use std::{collections::VecDeque, time::Duration};
+use tokio::time::Instant;
+
+/// Settings for the phi accrual failure detector that watches each member.
+///
+/// Every member sends a heartbeat each `heartbeat_interval`, and any frame
+/// received from a member counts as a heartbeat. The detector learns the
+/// distribution of gaps between heartbeats and computes `phi`, the confidence
+/// that the member has failed given how long it has been silent. A member is
+/// suspected once `phi` exceeds `phi_threshold`, and is alive again as soon
+/// as anything is received from it.
+#[derive(Clone, Copy, Debug)]
+pub struct FailureDetectorConfig {
+    pub heartbeat_interval: Duration,
+    /// Set to `f64::INFINITY` to never suspect a member.
+    pub phi_threshold: f64,
+    /// The number of recent heartbeat gaps used to estimate the distribution.
+    pub max_samples: usize,
+    /// A lower bound on the standard deviation of heartbeat gaps, so that a
+    /// very regular member is not suspected after a small delay.
+    pub min_std_dev: Duration,
+    /// Extra silence that is tolerated on top of the expected heartbeat gap.
+    pub acceptable_pause: Duration
+}
+
+impl Default for FailureDetectorConfig {
+    fn default() -> Self {
+        Self {
+            heartbeat_interval: Duration::from_millis(500),
+            phi_threshold: 8.0,
+            max_samples: 200,
+            min_std_dev: Duration::from_millis(100),
+            acceptable_pause: Duration::from_secs(1)
+        }
+    }
+}
+
+/// Tracks heartbeat arrivals from a single member.
+pub(crate) struct PhiAccrualDetector {
+    config: FailureDetectorConfig,
+    gaps: VecDeque<f64>,
+    last_heartbeat: Instant
+}
+
+impl PhiAccrualDetector {
+    pub fn new(config: FailureDetectorConfig) -> Self {
+        // Assume heartbeats arrive on schedule until some have been observed
+        let mut gaps = VecDeque::with_capacity(config.max_samples);
+        gaps.push_back(config.heartbeat_interval.as_secs_f64() * 1000.0);
+
+        Self {
+            config,
+            gaps,
+            last_heartbeat: Instant::now()
+        }
+    }
+
+    pub fn heartbeat(&mut self) {
+        let now = Instant::now();
+        let gap = (now - self.last_heartbeat).as_secs_f64() * 1000.0;
+        self.last_heartbeat = now;
+
+        if self.gaps.len() >= self.config.max_samples.max(1) {
+            self.gaps.pop_front();
+        }
+        self.gaps.push_back(gap);
+    }
+
+    /// Forget how long it has been since the last heartbeat, for when this
+    /// node stopped reading from the member for reasons of its own.
+    pub fn restart(&mut self) {
+        self.last_heartbeat = Instant::now();
+    }
+
+    /// The suspicion level for the member right now.
+    pub fn phi(&self) -> f64 {
+        let n = self.gaps.len() as f64;
+        let mean = self.gaps.iter().sum::<f64>() / n;
+        let variance = self.gaps.iter().map(|g| (g - mean).powi(2)).sum::<f64>() / n;
+
+        let min_std_dev = self.config.min_std_dev.as_secs_f64() * 1000.0;
+        let std_dev = variance.sqrt().max(min_std_dev);
+        let mean = mean + self.config.acceptable_pause.as_secs_f64() * 1000.0;
+        let elapsed = self.last_heartbeat.elapsed().as_secs_f64() * 1000.0;
+
+        // Logistic approximation of the normal CDF
+        let y = (elapsed - mean) / std_dev;
+        let e = (-y * (1.5976 + 0.070566 * y * y)).exp();
+        if elapsed > mean {
+            -(e / (1.0 + e)).log10()
+        } else {
+            -(1.0 - 1.0 / (1.0 + e)).log10()
+        }
+    }
+
+    pub fn is_suspect(&self) -> bool {
+        self.phi() > self.config.phi_threshold
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio::time;
+
+    fn config() -> FailureDetectorConfig {
+        FailureDetectorConfig {
+            heartbeat_interval: Duration::from_millis(100),
+            phi_threshold: 8.0,
+            max_samples: 10,
+            min_std_dev: Duration::from_millis(20),
+            acceptable_pause: Duration::ZERO
+        }
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn phi_grows_with_silence() {
+        let mut detector = PhiAccrualDetector::new(config());
+        for _ in 0..10 {
+            time::advance(Duration::from_millis(100)).await;
+            detector.heartbeat();
+        }
+        assert!(detector.phi() < 1.0);
+
+        let mut last = detector.phi();
+        for _ in 0..4 {
+            time::advance(Duration::from_millis(50)).await;
+            assert!(detector.phi() > last);
+            last = detector.phi();
+        }
+        assert!(!detector.is_suspect());
+
+        time::advance(Duration::from_millis(100)).await;
+        assert!(detector.is_suspect());
+
+        detector.heartbeat();
+        assert!(!detector.is_suspect());
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn tolerates_acceptable_pause() {
+        let mut detector = PhiAccrualDetector::new(FailureDetectorConfig { acceptable_pause: Duration::from_secs(1), ..config() });
+        detector.heartbeat();
+        time::advance(Duration::from_millis(1000)).await;
+        assert!(!detector.is_suspect());
+        time::advance(Duration::from_millis(500)).await;
+        assert!(detector.is_suspect());
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn restart_forgets_silence() {
+        let mut detector = PhiAccrualDetector::new(config());
+        time::advance(Duration::from_secs(5)).await;
+        assert!(detector.is_suspect());
+        detector.restart();
+        assert!(!detector.is_suspect());
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn keeps_max_samples() {
+        let mut detector = PhiAccrualDetector::new(config());
+        for _ in 0..20 {
+            time::advance(Duration::from_millis(100)).await;
+            detector.heartbeat();
+        }
+        assert_eq!(detector.gaps.len(), 10);
+    }
+
+    #[test]
+    fn never_suspects_with_infinite_threshold() {
+        let detector = PhiAccrualDetector::new(FailureDetectorConfig { phi_threshold: f64::INFINITY, ..config() });
+        assert!(!detector.is_suspect());
+    }
+}
@@ -0,0 +1,241 @@
+use super::{Config, NodeId, Address, WireFormat, Compression, Dissemination, tls::load_certificate};
+use serde::{Serialize, Deserialize};
+use tokio::io::{AsyncRead, AsyncWrite, AsyncReadExt, AsyncWriteExt};
+use std::io::{self, ErrorKind};
+
+/// Bumped whenever members built from different versions can no longer talk
+/// to each other.
+pub(crate) static PROTOCOL_VERSION: u16 = 1;
+
+/// Every handshake starts with these bytes, so that a stray connection from
+/// something else is turned away before anything is decoded.
+static MAGIC: &[u8; 4] = b"MCST";
+
+/// The largest handshake that is read, so a stray connection cannot make a
+/// node allocate an arbitrary amount of memory.
+static MAX_HANDSHAKE_LEN: u32 = 4096;
+
+/// The first message sent in each direction on every connection between
+/// members. Both sides check the other belongs to the same group before any
+/// messages are exchanged.
+///
+/// On the wire it is `MCST`, then a big-endian `u32` length, then the bincode
+/// encoding of this struct.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub(crate) struct Handshake {
+    pub version: u16,
+    pub cluster: String,
+    pub node_id: NodeId,
+    /// A hash of the member list in the config, with every member's name,
+    /// address and certificate, which must be the same on every member.
+    pub config_hash: u64,
+    pub wire_format: WireFormat,
+    pub max_message_size: u64,
+    /// The size of the length prefix of every frame after the handshake.
+    pub length_field_length: u8,
+    pub dissemination: Dissemination,
+    /// The algorithms the sender accepts compressed messages in, which is
+    /// none unless it enabled compression.
+    pub compression: Vec<Compression>,
+    /// Whether the sender reconnects to members after a network failure.
+    pub reconnect: bool,
+    /// Whether this connection replaces one to the same member that failed.
+    pub resuming: bool,
+    /// Whether the sender is joining a group that is already running.
+    pub joining: bool,
+    /// Whether the sender's group is already running, in which case nodes
+    /// that are not joining or resuming are turned away.
+    pub started: bool
+}
+
+/// FNV-1a, which unlike the standard library's hasher is guaranteed to give
+/// the same result on every build. Certificates are hashed by their contents
+/// and Unix sockets by their path as listed, since members may load the
+/// config and certificates from different directories.
+fn config_hash(config: &Config) -> u64 {
+    let mut hash: u64 = 0xcbf29ce484222325;
+    let mut write = |bytes: &[u8]| for byte in bytes {
+        hash ^= *byte as u64;
+        hash = hash.wrapping_mul(0x100000001b3);
+    };
+
+    // Every field is prefixed by its length, so that moving bytes from one
+    // field to the next changes the hash
+    let mut write_field = |bytes: &[u8]| {
+        write(&(bytes.len() as u64).to_be_bytes());
+        write(bytes);
+    };
+
+    write_field(&(config.len() as u64).to_be_bytes());
+    for node in (0..config.len()).filter_map(|id| config.get(id)) {
+        write_field(node.name.as_bytes());
+        match &node.listed_address {
+            Address::Tcp { host, port } => {
+                write_field(host.as_bytes());
+                write_field(&port.to_be_bytes());
+            },
+            Address::Unix(path) => {
+                write_field(b"unix:");
+                write_field(path.as_os_str().as_encoded_bytes());
+            }
+        }
+        // A certificate that cannot be loaded fails TLS setup before any
+        // handshake is sent
+        match node.certificate.as_deref().map(load_certificate) {
+            Some(Ok(certificate)) => write_field(certificate.as_ref()),
+            Some(Err(_)) | None => write_field(&[])
+        }
+    }
+
+    hash
+}
+
+impl Handshake {
+    pub fn new(node_id: NodeId, config: &Config) -> Self {
+        Self {
+            version: PROTOCOL_VERSION,
+            cluster: config.options.cluster_name.clone(),
+            node_id,
+            config_hash: config_hash(config),
+            wire_format: config.options.wire_format,
+            max_message_size: config.options.max_message_size as u64,
+            length_field_length: config.options.length_field_length as u8,
+            dissemination: config.options.dissemination,
+            compression: match config.options.compression {
+                Some(_) => Compression::ALL.to_vec(),
+                None => Vec::new()
+            },
+            reconnect: config.options.reconnect_window.is_some(),
+            resuming: false,
+            joining: false,
+            started: false
+        }
+    }
+
+    pub async fn write<S>(&self, stream: &mut S) -> io::Result<()> where S: AsyncWrite + Unpin {
+        let encoded = bincode::serialize(self).map_err(|e| io::Error::new(ErrorKind::InvalidData, e))?;
+
+        let mut frame = Vec::with_capacity(MAGIC.len() + 4 + encoded.len());
+        frame.extend_from_slice(MAGIC);
+        frame.extend_from_slice(&(encoded.len() as u32).to_be_bytes());
+        frame.extend_from_slice(&encoded);
+
+        stream.write_all(&frame).await?;
+        stream.flush().await
+    }
+
+    /// Read a handshake, reading no further so that no frames sent right after
+    /// it are lost.
+    pub async fn read<S>(stream: &mut S) -> io::Result<Self> where S: AsyncRead + Unpin {
+        let mut magic = [0u8; 4];
+        stream.read_exact(&mut magic).await?;
+        if magic != *MAGIC {
+            return Err(io::Error::new(ErrorKind::InvalidData, "not a multicast handshake"));
+        }
+
+        let len = stream.read_u32().await?;
+        if len > MAX_HANDSHAKE_LEN {
+            return Err(io::Error::new(ErrorKind::InvalidData, format!("handshake is too long ({len} bytes)")));
+        }
+
+        let mut encoded = vec![0u8; len as usize];
+        stream.read_exact(&mut encoded).await?;
+        bincode::deserialize(&encoded).map_err(|e| io::Error::new(ErrorKind::InvalidData, e))
+    }
+
+    /// Check that a peer that sent `theirs` belongs to the same group as this
+    /// node, which sends `self`. Which node the peer claims to be is up to the
+    /// caller to check.
+    pub fn check(&self, theirs: &Handshake) -> Result<(), String> {
+        if theirs.version != self.version {
+            Err(format!("protocol version {} does not match ours ({})", theirs.version, self.version))
+        } else if theirs.cluster != self.cluster {
+            Err(format!("cluster {:?} does not match ours ({:?})", theirs.cluster, self.cluster))
+        } else if theirs.config_hash != self.config_hash {
+            Err(format!("config hash {:016x} does not match ours ({:016x})", theirs.config_hash, self.config_hash))
+        } else if theirs.wire_format != self.wire_format {
+            Err(format!("wire format {:?} does not match ours ({:?})", theirs.wire_format, self.wire_format))
+        } else if theirs.max_message_size != self.max_message_size {
+            Err(format!("maximum message size {} does not match ours ({})", theirs.max_message_size, self.max_message_size))
+        } else if theirs.length_field_length != self.length_field_length {
+            Err(format!("length field length {} does not match ours ({})", theirs.length_field_length, self.length_field_length))
+        } else if theirs.dissemination != self.dissemination {
+            Err(format!("dissemination {:?} does not match ours ({:?})", theirs.dissemination, self.dissemination))
+        } else {
+            Ok(())
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config_file;
+    use std::path::Path;
+
+    fn handshake() -> Handshake {
+        Handshake::new(0, &Config::new())
+    }
+
+    #[tokio::test]
+    async fn round_trips() {
+        let (mut ours, mut theirs) = tokio::io::duplex(1024);
+        let sent = Handshake { joining: true, ..handshake() };
+        sent.write(&mut ours).await.unwrap();
+        ours.write_all(b"next frame").await.unwrap();
+
+        let received = Handshake::read(&mut theirs).await.unwrap();
+        assert!(received.joining);
+        assert!(sent.check(&received).is_ok());
+        let mut next = [0u8; 10];
+        theirs.read_exact(&mut next).await.unwrap();
+        assert_eq!(&next, b"next frame");
+    }
+
+    #[tokio::test]
+    async fn rejects_other_protocols() {
+        let (mut ours, mut theirs) = tokio::io::duplex(1024);
+        ours.write_all(b"GET / HTTP/1.1\r\n").await.unwrap();
+        assert_eq!(Handshake::read(&mut theirs).await.unwrap_err().kind(), ErrorKind::InvalidData);
+
+        let (mut ours, mut theirs) = tokio::io::duplex(1024);
+        ours.write_all(MAGIC).await.unwrap();
+        ours.write_u32(MAX_HANDSHAKE_LEN + 1).await.unwrap();
+        assert_eq!(Handshake::read(&mut theirs).await.unwrap_err().kind(), ErrorKind::InvalidData);
+    }
+
+    #[test]
+    fn checks_group_settings() {
+        let ours = handshake();
+        let mismatched = [
+            Handshake { version: PROTOCOL_VERSION + 1, ..handshake() },
+            Handshake { cluster: "other".into(), ..handshake() },
+            Handshake { config_hash: ours.config_hash ^ 1, ..handshake() },
+            Handshake { wire_format: WireFormat::Json, ..handshake() },
+            Handshake { max_message_size: 1, ..handshake() },
+            Handshake { length_field_length: 2, ..handshake() },
+            Handshake { dissemination: Dissemination::Tree { fanout: 2 }, ..handshake() },
+            Handshake { dissemination: Dissemination::Gossip { fanout: 3 }, ..handshake() }
+        ];
+        for theirs in &mismatched {
+            assert!(ours.check(theirs).is_err(), "{:?} was accepted", theirs);
+        }
+
+        // Compression is agreed between each pair of members instead
+        let theirs = Handshake { compression: Compression::ALL.to_vec(), reconnect: true, ..handshake() };
+        assert!(ours.check(&theirs).is_ok());
+    }
+    #[test]
+    fn config_hash_ignores_config_directory() {
+        let source = "[[nodes]]\nname = \"a\"\nunix = \"a.sock\"\n\n[[nodes]]\nname = \"b\"\nhost = \"localhost\"\nport = 4000\n";
+        let hash = |dir: &str, source: &str| match config_file::parse_toml(source, Path::new(dir), "a") {
+            Ok((config, _)) => config_hash(&config),
+            Err(e) => panic!("config was rejected: {}", e.message)
+        };
+
+        assert_eq!(hash("", source), hash("/etc/group", source));
+        assert_eq!(hash("conf", source), hash("../other/conf", source));
+        assert_ne!(hash("", source), hash("", &source.replace("a.sock", "b.sock")));
+        assert_ne!(hash("", source), hash("", &source.replace("name = \"b\"", "name = \"c\"")));
+    }
+}
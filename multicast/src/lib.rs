@@ -3,23 +3,48 @@ mod total_order;
 mod protocol;
 mod reliable;
 mod config;
+mod config_file;
 mod member;
 mod basic;
 mod pipe;
+mod wal;
+mod failure_detector;
+mod builder;
+mod channel;
+mod tls;
+mod handshake;
+mod codec;
+mod stats;
+mod compression;
+mod reconnect;
+mod transport;
+mod retry;
+mod dissemination;
 
 use member::{MulticastMemberHandle, MemberStateMessage};
-pub use config::{Config, NodeId, parse_config};
-use tokio::sync::mpsc::UnboundedReceiver;
+pub use config::{Config, NodeId, NodeNames, parse_config};
 use std::collections::HashMap;
 
 type MulticastGroup = HashMap<NodeId, MulticastMemberHandle>;
-type IncomingChannel<M> = UnboundedReceiver<MemberStateMessage<M>>;
+type IncomingChannel<M> = channel::Receiver<MemberStateMessage<M>>;
 
-pub use total_order::TotalOrderedMulticast;
+pub use total_order::{TotalOrderedMulticast, FlushTimeout};
 pub use reliable::ReliableMulticast;
 pub use basic::BasicMulticast;
+pub use protocol::MessagePriority;
+pub use wal::{DeliveryLog, SyncPolicy};
+pub use failure_detector::FailureDetectorConfig;
+pub use builder::{MulticastBuilder, BatchConfig, QuorumConfig};
+pub use retry::{RetryStrategy, ConnectEvent};
+pub use dissemination::Dissemination;
+pub use channel::OverflowPolicy;
+pub use codec::{WireCodec, WireFormat, CodecError, Bincode, Json, MessagePack, Cbor};
+pub use stats::ConnectionStats;
+pub use compression::{Compression, CompressionConfig};
+pub use transport::Address;
 
 use serde::{Serialize, de::DeserializeOwned};
+use std::{fmt, io};
 use async_trait::async_trait;
 
 #[derive(Debug)]
@@ -27,14 +52,156 @@ pub enum MulticastError {
     BroadcastError(Vec<NodeId>),
     InvalidRecipient(NodeId),
     ClientDisconnected(NodeId),
+    /// The failure detector suspects this member has crashed or hung, though
+    /// its connection is still open.
+    ClientSuspected(NodeId),
+    /// A member that was suspected has been heard from again.
+    ClientRecovered(NodeId),
+    /// The outgoing queues of these members are full, so the message was not
+    /// sent to anyone. Only returned with `OverflowPolicy::Error`.
+    Backpressure(Vec<NodeId>),
+    /// The outgoing queues of these members are full, so the message was
+    /// dropped without being sent to anyone. Only returned with
+    /// `OverflowPolicy::Drop`.
+    Dropped(Vec<NodeId>),
+    /// The message could not be encoded in the group's wire format, so it
+    /// was not sent to anyone.
+    EncodeFailed(CodecError),
+    /// The message is `size` bytes once encoded, more than the group's
+    /// maximum of `max`, so it was not sent to anyone.
+    MessageTooLarge { size: usize, max: usize },
+    /// `origin`'s messages numbered from `from` up to `to` were lost, since no
+    /// member still had them. They are skipped, so that later messages from
+    /// `origin` are still delivered.
+    MessagesLost { origin: NodeId, from: usize, to: usize },
+    /// A message could not be appended to the delivery log, so it was not
+    /// delivered. No more messages are delivered after this.
+    LogFailed(io::Error),
     AllClientsDisconnected,
+    /// The task running the protocol stopped, so nothing more can be sent or
+    /// delivered.
     InternalError
 }
 
+/// Errors that prevent a node from joining its multicast group. Every variant
+/// lists the nodes that had not been admitted to the group when setup failed.
+#[derive(Debug)]
+pub enum ConnectError {
+    /// This node could not listen for connections from other members.
+    BindFailed { addr: Address, error: io::Error, missing: Vec<NodeId> },
+    /// A member this node is responsible for connecting to could not be reached.
+    PeerUnreachable { node_id: NodeId, error: io::Error, missing: Vec<NodeId> },
+    /// A member this node dialed answered with a handshake that could not be
+    /// read, that belongs to a differently configured group, or that came
+    /// from another node.
+    HandshakeMalformed { node_id: NodeId, reason: String, missing: Vec<NodeId> },
+    /// Not every member connected before the timeout expired.
+    TimedOut { secs: u64, missing: Vec<NodeId> },
+    /// No member of an already running group could be reached while joining.
+    GroupUnreachable { missing: Vec<NodeId> },
+    /// The certificates or private key needed for TLS could not be loaded.
+    TlsSetupFailed { error: io::Error, missing: Vec<NodeId> },
+    /// The group started without this node, which can only join it now.
+    AlreadyStarted { missing: Vec<NodeId> },
+    /// The options this node was configured with cannot work.
+    InvalidOptions { reason: String, missing: Vec<NodeId> }
+}
+
+impl MulticastError {
+    /// Describe the error with the names of the nodes it is about.
+    pub fn with_names<'a>(&'a self, names: &'a NodeNames) -> impl fmt::Display + 'a {
+        Named { error: self, names }
+    }
+
+    fn describe(&self, f: &mut fmt::Formatter<'_>, names: &NodeNames) -> fmt::Result {
+        use MulticastError::*;
+        match self {
+            BroadcastError(failed) => write!(f, "failed to send to nodes {}", names.list(failed)),
+            InvalidRecipient(node_id) => write!(f, "{} is not a member of the group", names.describe(*node_id)),
+            ClientDisconnected(node_id) => write!(f, "{} disconnected", names.describe(*node_id)),
+            ClientSuspected(node_id) => write!(f, "{} is suspected to have failed", names.describe(*node_id)),
+            ClientRecovered(node_id) => write!(f, "{} recovered", names.describe(*node_id)),
+            Backpressure(full) => write!(f, "the queues of nodes {} are full", names.list(full)),
+            Dropped(full) => write!(f, "dropped the message, the queues of nodes {} are full", names.list(full)),
+            EncodeFailed(error) => write!(f, "failed to encode message: {error}"),
+            MessageTooLarge { size, max } => write!(f, "message of {size} bytes is larger than the maximum of {max}"),
+            MessagesLost { origin, from, to } => 
+                write!(f, "messages {from} to {} from {} were lost", to - 1, names.describe(*origin)),
+            LogFailed(error) => write!(f, "failed to append to the delivery log: {error}"),
+            AllClientsDisconnected => write!(f, "every other member disconnected"),
+            InternalError => write!(f, "internal error")
+        }
+    }
+}
+
+impl fmt::Display for MulticastError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        self.describe(f, &NodeNames::default())
+    }
+}
+
+impl std::error::Error for MulticastError {}
+
+impl ConnectError {
+    /// Describe the error with the names of the nodes it is about.
+    pub fn with_names<'a>(&'a self, names: &'a NodeNames) -> impl fmt::Display + 'a {
+        Named { error: self, names }
+    }
+
+    fn describe(&self, f: &mut fmt::Formatter<'_>, names: &NodeNames) -> fmt::Result {
+        use ConnectError::*;
+        match self {
+            BindFailed { addr, error, missing } => 
+                write!(f, "failed to bind to {addr}: {error} (missing nodes: {})", names.list(missing)),
+            PeerUnreachable { node_id, error, missing } => 
+                write!(f, "failed to connect to {}: {error} (missing nodes: {})", names.describe(*node_id), names.list(missing)),
+            HandshakeMalformed { node_id, reason, missing } => 
+                write!(f, "bad handshake from {}: {reason} (missing nodes: {})", names.describe(*node_id), names.list(missing)),
+            TimedOut { secs, missing } => 
+                write!(f, "failed to connect to all nodes within {secs}s (missing nodes: {})", names.list(missing)),
+            GroupUnreachable { missing } => 
+                write!(f, "no member of the running group could be reached (missing nodes: {})", names.list(missing)),
+            TlsSetupFailed { error, missing } => 
+                write!(f, "failed to set up TLS: {error} (missing nodes: {})", names.list(missing)),
+            AlreadyStarted { missing } => 
+                write!(f, "the group has already started without this node, which can only join it (missing nodes: {})", 
+                    names.list(missing)),
+            InvalidOptions { reason, missing } => 
+                write!(f, "invalid options: {reason} (missing nodes: {})", names.list(missing))
+        }
+    }
+}
+
+impl fmt::Display for ConnectError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        self.describe(f, &NodeNames::default())
+    }
+}
+
+impl std::error::Error for ConnectError {}
+
+/// An error described with node names rather than ids.
+struct Named<'a, E> {
+    error: &'a E,
+    names: &'a NodeNames
+}
+
+impl fmt::Display for Named<'_, MulticastError> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        self.error.describe(f, self.names)
+    }
+}
+
+impl fmt::Display for Named<'_, ConnectError> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        self.error.describe(f, self.names)
+    }
+}
+
 #[async_trait]
 pub trait Multicast<M> where M: Send + Serialize {
     async fn connect(node_id: NodeId, configuration: Config, timeout_secs: u64) 
-        -> Self where M: 'static + Serialize + Send + DeserializeOwned;
+        -> Result<Self, ConnectError> where Self: Sized, M: 'static + Serialize + Send + DeserializeOwned;
 
     async fn broadcast(&mut self, msg: M) -> Result<(), MulticastError>;
 
@@ -1,18 +1,36 @@
 use super::config::NodeId;
+use super::builder::Options;
+use super::channel::Sender;
+use super::failure_detector::{FailureDetectorConfig, PhiAccrualDetector};
+use super::handshake::Handshake;
+use super::reconnect::Reconnector;
+use super::compression::{Compression, CompressionConfig, DecompressError};
+use super::codec::{WireCodec, WireFormat};
+use super::MulticastError;
+use super::stats::Counters;
+use super::BatchConfig;
 use tokio::{
-    sync::mpsc::{UnboundedSender, UnboundedReceiver, error::SendError}, 
-    task::JoinHandle, net::TcpStream, select
+    sync::{mpsc::{UnboundedSender, UnboundedReceiver, error::{SendError, TryRecvError}, unbounded_channel}, watch, Notify}, 
+    task::JoinHandle, select, time::{self, Instant, MissedTickBehavior},
+    io::{AsyncRead, AsyncWrite}
 };
+use std::{io, time::Duration, collections::VecDeque, sync::{Arc, atomic::{AtomicBool, AtomicUsize, AtomicU64, Ordering}}};
 use serde::{de::DeserializeOwned, Serialize};
-use tokio_util::codec::LengthDelimitedCodec;
+use tokio_util::{codec::{LengthDelimitedCodec, LengthDelimitedCodecError, FramedRead, FramedWrite}, bytes::{Bytes, BytesMut, BufMut}};
 use futures::{stream::StreamExt, SinkExt};
 use log::{trace, error};
 
 /// Represents any message types a member handler thread could send the multicast engine
 #[derive(Debug)]
 pub enum MemberStateMessageType<M> {
-    Message(M),
-    NetworkError
+    /// A decoded message and the frame it arrived in, which can be passed to
+    /// other members as is.
+    Message(M, Bytes),
+    NetworkError,
+    /// The failure detector suspects the member has crashed or hung.
+    Suspected,
+    /// A suspected member was heard from again.
+    Alive
 }
 
 /// Represents any messages a member handler thread could send the multicast engine.
@@ -22,16 +40,125 @@ pub struct MemberStateMessage<M> {
     pub member_id: NodeId
 }
 
+/// A smoothed estimate of the round trip time to a member, maintained from
+/// heartbeat acknowledgements the same way TCP estimates it (RFC 6298).
+#[derive(Clone, Copy, Debug)]
+pub(crate) struct RoundTripTime {
+    pub smoothed: Duration,
+    pub variance: Duration
+}
+
+impl RoundTripTime {
+    fn new(sample: Duration) -> Self {
+        Self { smoothed: sample, variance: sample / 2 }
+    }
+
+    fn update(&mut self, sample: Duration) {
+        let diff = self.smoothed.abs_diff(sample);
+        self.variance = self.variance * 3 / 4 + diff / 4;
+        self.smoothed = self.smoothed * 7 / 8 + sample / 8;
+    }
+
+    /// A round trip time that is rarely exceeded.
+    pub fn upper_bound(&self) -> Duration {
+        self.smoothed + self.variance * 4
+    }
+}
+
 /// The handle that the multicast engine has for each member handler thread.
 pub(super) struct MulticastMemberHandle {
     pub member_id: NodeId,
-    pub to_client: UnboundedSender<Vec<u8>>,
+    pub to_client: UnboundedSender<Bytes>,
+    /// The number of frames passed to the handler that it has not taken up to
+    /// write yet.
+    queued: Arc<AtomicUsize>,
+    /// The size in bytes of those frames.
+    queued_bytes: Arc<AtomicUsize>,
+    max_queued_bytes: usize,
+    /// Signalled when the member falls too far behind, so that the handler
+    /// gives up on it.
+    stalled: Arc<Notify>,
+    /// The latest round trip time estimate, once a heartbeat has been answered.
+    pub round_trip: watch::Receiver<Option<RoundTripTime>>,
     pub handle: JoinHandle<()>
 }
 
 impl MulticastMemberHandle {
-    pub fn pass_message(&self, msg: Vec<u8>) -> Result<(), SendError<Vec<u8>>> {
-        self.to_client.send(msg)
+    /// Spawn a handler task for a member connected over `socket`, which sent
+    /// the handshake `peer`. `drained` is signalled every time queued frames
+    /// are taken up to be written. If the connection fails, the handler tries
+    /// to `reconnect` before reporting a network error.
+    pub fn spawn<M, S, R>(socket: S, peer: &Handshake, to_engine: Sender<MemberStateMessage<M>>, 
+            options: &Options, drained: Arc<Notify>, counters: Arc<Counters>, reconnect: Option<R>) -> Self 
+            where M: 'static + Send + DeserializeOwned + Serialize,
+                  S: 'static + Send + AsyncRead + AsyncWrite,
+                  R: 'static + Reconnector<S> {
+        let member_id = peer.node_id;
+        let (to_client, from_engine) = unbounded_channel();
+        let (round_trip_snd, round_trip) = watch::channel(None);
+        let queued: Arc<AtomicUsize> = Default::default();
+        let queued_bytes: Arc<AtomicUsize> = Default::default();
+        let stalled: Arc<Notify> = Default::default();
+        let member_data = MulticastMemberData {
+            member_id,
+            to_engine,
+            detector: options.failure_detector,
+            round_trip: round_trip_snd,
+            format: options.wire_format,
+            max_message_size: options.max_message_size,
+            received: AtomicU64::new(0),
+            suspected: AtomicBool::new(false)
+        };
+        let outbox = MemberOutbox {
+            from_engine,
+            queued: queued.clone(),
+            queued_bytes: queued_bytes.clone(),
+            stalled: stalled.clone(),
+            drained,
+            heartbeat_interval: options.failure_detector.heartbeat_interval,
+            batching: options.batching,
+            compression: options.compression.filter(|config| peer.compression.contains(&config.algorithm)),
+            header_len: options.length_field_length,
+            counters,
+            resumable: reconnect.is_some(),
+            unacked: VecDeque::new(),
+            acked: 0,
+            last_ack_sent: 0
+        };
+
+        Self {
+            member_id,
+            to_client,
+            queued,
+            queued_bytes,
+            max_queued_bytes: options.max_queued_bytes,
+            stalled,
+            round_trip,
+            handle: tokio::spawn(member_loop(socket, member_data, outbox, reconnect, options.length_field_length))
+        }
+    }
+
+    /// Queue a frame made by `data_frame` to be written to the member. If the
+    /// frame would take the queue past its limit, the frame is refused and
+    /// the handler reports the member as failed.
+    pub fn pass_message(&self, frame: Bytes) -> Result<(), SendError<Bytes>> {
+        let size = frame.len();
+        if self.queued_bytes.fetch_add(size, Ordering::Relaxed) + size > self.max_queued_bytes {
+            self.queued_bytes.fetch_sub(size, Ordering::Relaxed);
+            error!("More than {} bytes are queued for node {}, giving up on it", self.max_queued_bytes, self.member_id);
+            self.stalled.notify_one();
+            return Err(SendError(frame));
+        }
+
+        self.queued.fetch_add(1, Ordering::Relaxed);
+        self.to_client.send(frame).inspect_err(|_| {
+            self.queued.fetch_sub(1, Ordering::Relaxed);
+            self.queued_bytes.fetch_sub(size, Ordering::Relaxed);
+        })
+    }
+
+    pub fn queued(&self) -> usize {
+        self.queued.load(Ordering::Relaxed)
     }
 }
 
@@ -42,10 +169,20 @@ impl Drop for MulticastMemberHandle {
     }
 }
 
-pub(super) struct MulticastMemberData<M> {
+/// The state of the half of a member handler that reads from the member.
+struct MulticastMemberData<M> {
     pub member_id: NodeId,
-    pub to_engine: UnboundedSender<MemberStateMessage<M>>,
-    pub from_engine: UnboundedReceiver<Vec<u8>>
+    pub to_engine: Sender<MemberStateMessage<M>>,
+    pub detector: FailureDetectorConfig,
+    pub round_trip: watch::Sender<Option<RoundTripTime>>,
+    pub format: WireFormat,
+    pub max_message_size: usize,
+    /// The number of data frames from the member that were passed to the
+    /// engine, or skipped because they could not be read.
+    pub received: AtomicU64,
+    /// Whether the engine was told the member is suspected, which carries
+    /// over to a new connection after reconnecting.
+    pub suspected: AtomicBool
 }
 
 impl<M> MulticastMemberData<M> {
@@ -56,45 +193,471 @@ impl<M> MulticastMemberData<M> {
         }
     }
     
-    fn notify_client_message(&mut self, msg: MemberStateMessageType<M>) -> Result<(), SendError<MemberStateMessage<M>>> {
-        self.to_engine.send(self.generate_state_msg(msg))
+    /// Waits while the engine's incoming queue is full, which stops this
+    /// member's frames from being read until the engine catches up.
+    async fn notify_client_message(&self, msg: MemberStateMessageType<M>) -> Result<(), SendError<MemberStateMessage<M>>> {
+        self.to_engine.send(self.generate_state_msg(msg)).await
     }
 
-    fn notify_network_error(&mut self) -> Result<(), SendError<MemberStateMessage<M>>> {
-        self.to_engine.send(self.generate_state_msg(MemberStateMessageType::NetworkError))
+    async fn notify_network_error(&self) -> Result<(), SendError<MemberStateMessage<M>>> {
+        self.to_engine.send(self.generate_state_msg(MemberStateMessageType::NetworkError)).await
     }
 }
 
-pub(super) async fn member_loop<M>(socket: TcpStream, mut member_data: MulticastMemberData<M>) where M: 'static + DeserializeOwned + Serialize {
-    let mut stream = LengthDelimitedCodec::builder()
-        .length_field_type::<u32>()
-        .new_framed(socket);
+/// The state of the half of a member handler that writes to the member.
+struct MemberOutbox {
+    pub from_engine: UnboundedReceiver<Bytes>,
+    pub queued: Arc<AtomicUsize>,
+    pub queued_bytes: Arc<AtomicUsize>,
+    /// Signalled when the engine gives up on the member.
+    pub stalled: Arc<Notify>,
+    pub drained: Arc<Notify>,
+    pub heartbeat_interval: Duration,
+    pub batching: Option<BatchConfig>,
+    /// Messages are sent uncompressed if `None`.
+    pub compression: Option<CompressionConfig>,
+    /// The size of the length prefix in front of every frame.
+    pub header_len: usize,
+    pub counters: Arc<Counters>,
+    /// Whether data frames are kept until the member acknowledges them, so
+    /// they can be sent again over a new connection.
+    pub resumable: bool,
+    /// The data frames sent that the member has not acknowledged, oldest
+    /// first.
+    pub unacked: VecDeque<Bytes>,
+    /// The number of data frames the member has acknowledged.
+    pub acked: u64,
+    /// The number of data frames from the member this node last acknowledged.
+    pub last_ack_sent: u64
+}
+
+impl MemberOutbox {
+    /// Take up a frame the engine queued to be written, keeping it until it is
+    /// acknowledged if the connection can be resumed.
+    fn take(&mut self, frame: Bytes) -> Bytes {
+        self.queued.fetch_sub(1, Ordering::Relaxed);
+        self.queued_bytes.fetch_sub(frame.len(), Ordering::Relaxed);
+        self.drained.notify_one();
+        if self.resumable {
+            self.unacked.push_back(frame.clone());
+        }
+        self.compress(frame)
+    }
+
+    /// The member has received the first `count` data frames sent to it.
+    fn acknowledge(&mut self, count: u64) {
+        while self.acked < count && self.unacked.pop_front().is_some() {
+            self.acked += 1;
+        }
+    }
+
+    /// Compress a data frame if it is worth it on this connection.
+    fn compress(&self, frame: Bytes) -> Bytes {
+        let payload_len = frame.len() - 1;
+        let compressed = match self.compression {
+            Some(config) if payload_len >= config.threshold => {
+                let mut compressed = vec![compressed_frame_kind(config.algorithm)];
+                config.algorithm.compress_into(&frame[1..], &mut compressed);
+                Some(compressed).filter(|compressed| compressed.len() < frame.len())
+            },
+            _ => None
+        };
+
+        let frame = compressed.map_or(frame, Bytes::from);
+        self.counters.record_payload(payload_len as u64, (frame.len() - 1) as u64);
+        frame
+    }
+}
+
+/// Why one half of a member handler stopped.
+enum Exit<S> {
+    NetworkError,
+    /// The member broke the protocol, so it is not reconnected to.
+    Misbehaved,
+    /// Too many frames are queued for the member, so it is not reconnected
+    /// to either.
+    Stalled,
+    /// The member opened a new connection to replace this one.
+    Replaced(S),
+    EngineGone
+}
+
+/// What the half of a member handler that reads tells the half that writes.
+enum Control {
+    /// Echo a heartbeat sent at this time.
+    Heartbeat(u64),
+    /// The member has received this many data frames from this node.
+    Acknowledged(u64)
+}
+
+/// Every frame starts with one of these bytes so that heartbeats can be told
+/// apart from messages. A heartbeat carries the time it was sent, which the
+/// member echoes back in an acknowledgement to measure the round trip time.
+static DATA_FRAME: u8 = 0;
+static HEARTBEAT_FRAME: u8 = 1;
+static HEARTBEAT_ACK_FRAME: u8 = 2;
+/// A data frame whose payload is compressed.
+static LZ4_FRAME: u8 = 3;
+static ZSTD_FRAME: u8 = 4;
+/// The number of data frames received from the member, sent with every
+/// heartbeat if the connection can be resumed.
+static DATA_ACK_FRAME: u8 = 5;
+/// The first frame sent in each direction over a connection that replaces a
+/// failed one, carrying the number of data frames received.
+static RESUME_FRAME: u8 = 6;
+
+/// How long to wait for the member's resume frame on a new connection.
+static RESUME_TIMEOUT: Duration = Duration::from_secs(5);
+
+fn compressed_frame_kind(algorithm: Compression) -> u8 {
+    match algorithm {
+        Compression::Lz4 => LZ4_FRAME,
+        Compression::Zstd => ZSTD_FRAME
+    }
+}
+
+fn frame_compression(kind: u8) -> Option<Compression> {
+    Compression::ALL.into_iter().find(|algorithm| compressed_frame_kind(*algorithm) == kind)
+}
+
+/// Encode `msg` into a frame that can be passed to any number of members,
+/// unless its encoding is longer than `max_message_size`.
+pub(crate) fn data_frame<M>(format: WireFormat, max_message_size: usize, msg: &M) -> Result<Bytes, MulticastError> 
+        where M: Serialize {
+    let mut frame = vec![DATA_FRAME];
+    format.encode_into(msg, &mut frame).map_err(MulticastError::EncodeFailed)?;
+
+    let size = frame.len() - 1;
+    match size > max_message_size {
+        true => Err(MulticastError::MessageTooLarge { size, max: max_message_size }),
+        false => Ok(frame.into())
+    }
+}
+
+/// A frame that carries a single number, such as a heartbeat.
+fn control_frame(kind: u8, value: u64) -> Bytes {
+    let mut frame = BytesMut::with_capacity(9);
+    frame.put_u8(kind);
+    frame.put_u64(value);
+    frame.freeze()
+}
+
+/// Read frames from the member and pass them to the engine, and suspect the
+/// member if it goes quiet. Heartbeats are answered through `control`.
+async fn read_loop<M, R, S>(mut frames: FramedRead<R, LengthDelimitedCodec>, member_data: &MulticastMemberData<M>, 
+        control: UnboundedSender<Control>, epoch: Instant) -> Exit<S> 
+        where M: DeserializeOwned, R: AsyncRead + Unpin {
+    let mut round_trip: Option<RoundTripTime> = None;
+    let mut detector = PhiAccrualDetector::new(member_data.detector);
+    let mut check = time::interval(member_data.detector.heartbeat_interval);
+    check.set_missed_tick_behavior(MissedTickBehavior::Delay);
 
     loop {
         select! {
-            Some(to_send) = member_data.from_engine.recv() => {
-                let bytes = bincode::serialize(&to_send).unwrap();
-                if stream.send(bytes.into()).await.is_err() {
-                    member_data.notify_network_error();
-                    break;
+            _ = check.tick() => {
+                if !member_data.suspected.load(Ordering::Relaxed) && detector.is_suspect() {
+                    error!("Suspecting node {} (phi = {:.1})", member_data.member_id, detector.phi());
+                    member_data.suspected.store(true, Ordering::Relaxed);
+                    if member_data.notify_client_message(MemberStateMessageType::Suspected).await.is_err() {
+                        return Exit::EngineGone;
+                    }
                 }
             },
-            received = stream.next() => match received {
-                Some(Ok(bytes)) => {
-                    let msg = match bincode::deserialize(&bytes) {
-                        Ok(m) => MemberStateMessageType::Message(m),
-                        Err(e) => {
-                            error!("deserialize error on client handler {}: {:?}", member_data.member_id, e);
-                            continue
+            received = frames.next() => {
+                let frame = match received {
+                    Some(Ok(frame)) => frame.freeze(),
+                    Some(Err(e)) if e.get_ref().is_some_and(|e| e.is::<LengthDelimitedCodecError>()) => {
+                        error!("Disconnecting node {}: it sent a frame larger than the maximum message size ({} bytes)", 
+                            member_data.member_id, member_data.max_message_size);
+                        return Exit::Misbehaved
+                    },
+                    _ => return Exit::NetworkError
+                };
+
+                detector.heartbeat();
+                if member_data.suspected.load(Ordering::Relaxed) {
+                    trace!("Node {} is alive again", member_data.member_id);
+                    member_data.suspected.store(false, Ordering::Relaxed);
+                    if member_data.notify_client_message(MemberStateMessageType::Alive).await.is_err() {
+                        return Exit::EngineGone;
+                    }
+                }
+
+                let frame = match frame.split_first() {
+                    Some((kind, _)) if *kind == DATA_FRAME => frame.clone(),
+                    Some((kind, compressed)) if frame_compression(*kind).is_some() => {
+                        let algorithm = frame_compression(*kind).unwrap();
+                        let mut decompressed = vec![DATA_FRAME];
+                        match algorithm.decompress(compressed, member_data.max_message_size) {
+                            Ok(payload) => decompressed.extend_from_slice(&payload),
+                            Err(DecompressError::TooLarge) => {
+                                error!("Disconnecting node {}: it sent a message larger than the maximum message size ({} bytes)", 
+                                    member_data.member_id, member_data.max_message_size);
+                                return Exit::Misbehaved
+                            },
+                            Err(DecompressError::Corrupt(e)) => {
+                                error!("decompress error on client handler {}: {}", member_data.member_id, e);
+                                member_data.received.fetch_add(1, Ordering::Relaxed);
+                                continue
+                            }
                         }
-                    };
-                    member_data.notify_client_message(msg);
-                },
-                _ => {
-                    member_data.notify_network_error();
-                    break;
+                        Bytes::from(decompressed)
+                    },
+                    Some((kind, sent)) if *kind == HEARTBEAT_FRAME => {
+                        if let Ok(sent) = <[u8; 8]>::try_from(sent) {
+                            let _ = control.send(Control::Heartbeat(u64::from_be_bytes(sent)));
+                        }
+                        continue
+                    },
+                    Some((kind, sent)) if *kind == HEARTBEAT_ACK_FRAME => {
+                        if let Ok(sent) = <[u8; 8]>::try_from(sent) {
+                            let sent = Duration::from_micros(u64::from_be_bytes(sent));
+                            let sample = epoch.elapsed().saturating_sub(sent);
+                            match round_trip.as_mut() {
+                                Some(rtt) => rtt.update(sample),
+                                None => round_trip = Some(RoundTripTime::new(sample))
+                            }
+                            let _ = member_data.round_trip.send(round_trip);
+                        }
+                        continue
+                    },
+                    Some((kind, count)) if *kind == DATA_ACK_FRAME => {
+                        if let Ok(count) = <[u8; 8]>::try_from(count) {
+                            let _ = control.send(Control::Acknowledged(u64::from_be_bytes(count)));
+                        }
+                        continue
+                    },
+                    _ => {
+                        error!("malformed frame on client handler {}", member_data.member_id);
+                        continue
+                    }
+                };
+
+                let msg = match member_data.format.decode(&frame[1..]) {
+                    Ok(m) => MemberStateMessageType::Message(m, frame.clone()),
+                    Err(e) => {
+                        error!("deserialize error on client handler {}: {:?}", member_data.member_id, e);
+                        member_data.received.fetch_add(1, Ordering::Relaxed);
+                        continue
+                    }
+                };
+
+                let waiting_since = Instant::now();
+                if member_data.notify_client_message(msg).await.is_err() {
+                    trace!("engine for client handler {} is gone, stopping", member_data.member_id);
+                    return Exit::EngineGone;
+                }
+                // Only counted once passed on, since a frame being passed on
+                // when the connection fails is sent again after reconnecting
+                member_data.received.fetch_add(1, Ordering::Relaxed);
+
+                // The member was not read while the engine was full, so that
+                // time says nothing about whether it is alive
+                if waiting_since.elapsed() >= member_data.detector.heartbeat_interval {
+                    detector.restart();
+                }
+            }
+        }
+    }
+}
+
+/// Write a single frame and flush it.
+async fn write_frame<W>(frames: &mut FramedWrite<W, LengthDelimitedCodec>, outbox: &MemberOutbox, frame: Bytes) -> io::Result<()> 
+        where W: AsyncWrite + Unpin {
+    let size = outbox.header_len + frame.len();
+    frames.send(frame).await?;
+    outbox.counters.record_write(1, size as u64);
+    Ok(())
+}
+
+/// Write `first` together with any frames the engine queues within the
+/// batching budget, and flush them all at once.
+async fn write_batch<W>(frames: &mut FramedWrite<W, LengthDelimitedCodec>, outbox: &mut MemberOutbox, first: Bytes, 
+        batch: BatchConfig) -> io::Result<()> 
+        where W: AsyncWrite + Unpin {
+    let deadline = Instant::now() + batch.max_delay;
+    let (mut count, mut size) = (0, 0);
+    let mut next = Some(first);
+
+    while let Some(frame) = next.take() {
+        let frame = outbox.take(frame);
+        count += 1;
+        size += outbox.header_len + frame.len();
+        frames.feed(frame).await?;
+        if size >= batch.max_bytes {
+            break
+        }
+
+        next = match outbox.from_engine.try_recv() {
+            Ok(frame) => Some(frame),
+            Err(TryRecvError::Empty) => time::timeout_at(deadline, outbox.from_engine.recv()).await.ok().flatten(),
+            Err(TryRecvError::Disconnected) => None
+        };
+    }
+
+    SinkExt::<Bytes>::flush(frames).await?;
+    outbox.counters.record_write(count, size as u64);
+    Ok(())
+}
+
+/// Write the engine's messages, heartbeats and acknowledgements to the
+/// member. `received` is the number of data frames received from the member.
+async fn write_loop<W, S>(mut frames: FramedWrite<W, LengthDelimitedCodec>, outbox: &mut MemberOutbox, 
+        mut control: UnboundedReceiver<Control>, received: &AtomicU64, epoch: Instant) -> Exit<S> 
+        where W: AsyncWrite + Unpin {
+    let mut heartbeat = time::interval(outbox.heartbeat_interval);
+    heartbeat.set_missed_tick_behavior(MissedTickBehavior::Delay);
+    if let Some(batch) = outbox.batching {
+        // Otherwise frames are written out every 8 KiB regardless of the budget
+        frames.set_backpressure_boundary(batch.max_bytes);
+    }
+
+    loop {
+        let sent = select! {
+            frame = outbox.from_engine.recv() => {
+                let frame = match frame {
+                    Some(f) => f,
+                    None => return Exit::EngineGone
+                };
+
+                match outbox.batching {
+                    Some(batch) => write_batch(&mut frames, outbox, frame, batch).await,
+                    None => {
+                        let frame = outbox.take(frame);
+                        write_frame(&mut frames, outbox, frame).await
+                    }
+                }
+            },
+            Some(control) = control.recv() => match control {
+                Control::Heartbeat(sent_micros) => write_frame(&mut frames, outbox, control_frame(HEARTBEAT_ACK_FRAME, sent_micros)).await,
+                Control::Acknowledged(count) => {
+                    outbox.acknowledge(count);
+                    Ok(())
+                }
+            },
+            _ = heartbeat.tick() => {
+                let sent_micros = epoch.elapsed().as_micros() as u64;
+                let received = received.load(Ordering::Relaxed);
+                match write_frame(&mut frames, outbox, control_frame(HEARTBEAT_FRAME, sent_micros)).await {
+                    Ok(()) if outbox.resumable && received > outbox.last_ack_sent => {
+                        outbox.last_ack_sent = received;
+                        write_frame(&mut frames, outbox, control_frame(DATA_ACK_FRAME, received)).await
+                    },
+                    sent => sent
                 }
             }
+        };
+
+        if sent.is_err() {
+            return Exit::NetworkError;
         }
     }
-}
\ No newline at end of file
+}
+
+/// Start a connection that replaces a failed one: tell the member how many
+/// data frames this node received, learn how many the member received in
+/// turn, and send again the ones it missed.
+async fn resume<R, W>(reader: &mut FramedRead<R, LengthDelimitedCodec>, writer: &mut FramedWrite<W, LengthDelimitedCodec>, 
+        received: u64, outbox: &mut MemberOutbox) -> io::Result<()> 
+        where R: AsyncRead + Unpin, W: AsyncWrite + Unpin {
+    writer.send(control_frame(RESUME_FRAME, received)).await?;
+    outbox.last_ack_sent = received;
+
+    let theirs = match time::timeout(RESUME_TIMEOUT, reader.next()).await {
+        Ok(Some(Ok(frame))) => match frame.split_first() {
+            Some((kind, count)) if *kind == RESUME_FRAME => <[u8; 8]>::try_from(count).ok().map(u64::from_be_bytes),
+            _ => None
+        },
+        _ => None
+    };
+    let theirs = theirs.ok_or_else(|| io::Error::new(io::ErrorKind::InvalidData, "the member did not resume the connection"))?;
+    outbox.acknowledge(theirs);
+
+    let missed: Vec<Bytes> = outbox.unacked.iter().cloned().collect();
+    let mut size = 0;
+    for frame in &missed {
+        let frame = outbox.compress(frame.clone());
+        size += outbox.header_len + frame.len();
+        writer.feed(frame).await?;
+    }
+    SinkExt::<Bytes>::flush(writer).await?;
+    if !missed.is_empty() {
+        outbox.counters.record_write(missed.len() as u64, size as u64);
+    }
+
+    Ok(())
+}
+
+/// Reading and writing run side by side, so a member whose messages cannot be
+/// passed on to a busy engine still gets heartbeats from this node.
+///
+/// If the connection fails and `reconnect` is given, the same member is
+/// reconnected to and the exchange carries on where it left off. A network
+/// error is only reported once reconnecting fails.
+async fn member_loop<M, S, R>(mut socket: S, member_data: MulticastMemberData<M>, mut outbox: MemberOutbox, 
+        mut reconnect: Option<R>, length_field_length: usize) 
+        where M: 'static + DeserializeOwned + Serialize, S: AsyncRead + AsyncWrite, R: Reconnector<S> {
+    // Data frames are a kind byte longer than the message, and heartbeats
+    // always fit
+    let codec = LengthDelimitedCodec::builder()
+        .length_field_length(length_field_length)
+        .max_frame_length(member_data.max_message_size.max(8) + 1)
+        .new_codec();
+    let epoch = Instant::now();
+    let mut resuming = false;
+    let stalled = outbox.stalled.clone();
+
+    loop {
+        let (rd, wr) = tokio::io::split(socket);
+        let mut reader = FramedRead::new(rd, codec.clone());
+        let mut writer = FramedWrite::new(wr, codec.clone());
+
+        let resumed = match resuming {
+            true => resume(&mut reader, &mut writer, member_data.received.load(Ordering::Relaxed), &mut outbox).await,
+            false => Ok(())
+        };
+        let exit = match resumed {
+            Ok(()) => {
+                let (control_snd, control_rcv) = unbounded_channel();
+                select! {
+                    exit = read_loop(reader, &member_data, control_snd, epoch) => exit,
+                    exit = write_loop(writer, &mut outbox, control_rcv, &member_data.received, epoch) => exit,
+                    _ = stalled.notified() => Exit::Stalled,
+                    stream = async {
+                        match reconnect.as_mut() {
+                            Some(r) => r.replacement().await,
+                            None => std::future::pending().await
+                        }
+                    } => Exit::Replaced(stream)
+                }
+            },
+            Err(e) => {
+                trace!("Could not resume the connection to node {}: {}", member_data.member_id, e);
+                Exit::NetworkError
+            }
+        };
+
+        let replacement = match exit {
+            Exit::EngineGone => return,
+            Exit::Replaced(stream) => Some(stream),
+            Exit::NetworkError => match reconnect.as_mut() {
+                Some(r) => {
+                    error!("Lost the connection to node {}, reconnecting...", member_data.member_id);
+                    r.reconnect().await
+                },
+                None => None
+            },
+            Exit::Misbehaved | Exit::Stalled => None
+        };
+
+        socket = match replacement {
+            Some(stream) => stream,
+            None => {
+                let _ = member_data.notify_network_error().await;
+                return
+            }
+        };
+        trace!("Reconnected to node {}", member_data.member_id);
+        resuming = true;
+    }
+}
@@ -1,4 +1,3 @@
-use log::error;
 use tokio::sync::mpsc::{unbounded_channel, UnboundedReceiver, UnboundedSender};
 
 pub(crate) struct UnboundedPipe<I, O> {
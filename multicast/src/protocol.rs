@@ -7,13 +7,23 @@ pub enum TotalOrderNetworkMessage<M> {
     PriorityRequest(PriorityRequestArgs<M>),
     PriorityProposal(PriorityProposalArgs),
     PriorityMessage(PriorityMessageArgs),
-    DirectMessage(M)
+    DirectMessage(M),
+    JoinSnapshot(JoinSnapshotArgs<M>)
+}
+
+/// A message that is placed in the total order.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub enum OrderedMessage<M> {
+    /// A message broadcast by the application.
+    Application(M),
+    /// The given node is admitted to the group at this point in the total order.
+    Join(NodeId)
 }
 
 #[derive(Clone, Debug, Serialize, Deserialize)]
 pub struct PriorityRequestArgs<M> {
     pub local_id: MessageId,
-    pub message: M
+    pub message: OrderedMessage<M>
 }
 
 #[derive(Clone, Debug, Serialize, Deserialize)]
@@ -28,12 +38,33 @@ pub struct PriorityMessageArgs {
     pub priority: MessagePriority,
 }
 
+/// Sent by each member to a joining node once the member delivers the join.
+/// Lists every message the member has yet to deliver, all of which are ordered
+/// after the joining node's admission point.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct JoinSnapshotArgs<M> {
+    pub sender: NodeId,
+    pub pending: Vec<PendingMessage<M>>
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct PendingMessage<M> {
+    pub local_id: MessageId,
+    pub message: OrderedMessage<M>,
+    pub priority: MessagePriority,
+    pub is_deliverable: bool
+}
+
 #[derive(Clone, Copy, Debug, Serialize, Deserialize, Hash, PartialEq, Eq)]
 pub struct MessageId {
     pub original_sender: NodeId,
+    /// Tells apart the messages of a node that rejoined from those it sent
+    /// before it crashed, which other members may still have queued.
+    pub incarnation: u64,
     pub local_id: usize
 }
 
+/// The priority agreed for a message, which is its position in the total order.
 #[derive(Clone, Copy, Debug, Serialize, Deserialize)]
 pub struct MessagePriority {
     pub priority: usize,
@@ -51,10 +82,7 @@ impl Ord for MessagePriority {
 
 impl PartialOrd for MessagePriority {
     fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
-        match self.priority.cmp(&other.priority) {
-            Ordering::Equal => Some(self.proposer.cmp(&other.proposer)),
-            x => Some(x)
-        }
+        Some(self.cmp(other))
     }
 }
 
@@ -0,0 +1,137 @@
+use super::{
+    NodeId, Address, builder::Options, retry::RetryPolicy, tls::{Tls, PeerStream}, handshake::Handshake, connection_pool::connect_to_node
+};
+use tokio::{sync::mpsc::{UnboundedSender, UnboundedReceiver, unbounded_channel}, time};
+use async_trait::async_trait;
+use std::{collections::HashMap, sync::{Arc, Mutex}, time::Duration};
+use log::trace;
+
+/// The member handlers that wait for their member to open a new connection
+/// when the old one fails, by member.
+#[derive(Clone, Default)]
+pub(crate) struct Reconnections {
+    waiting: Arc<Mutex<HashMap<NodeId, UnboundedSender<PeerStream>>>>
+}
+
+impl Reconnections {
+    /// Pass a new connection from `node_id` to the handler for that member.
+    pub fn resume(&self, node_id: NodeId, stream: PeerStream) -> Result<(), String> {
+        match self.waiting.lock().unwrap().get(&node_id) {
+            Some(handler) => handler.send(stream).map_err(|_| format!("the handler for node {} has stopped", node_id)),
+            None => Err(format!("node {} has no connection to resume", node_id))
+        }
+    }
+}
+
+/// Where a member handler gets a new connection of type `S` to its member
+/// when the old one fails.
+#[async_trait]
+pub(crate) trait Reconnector<S>: Send {
+    /// A new connection to the member, or `None` if none was made in time.
+    async fn reconnect(&mut self) -> Option<S>;
+
+    /// A new connection the member opened while the old one seemed fine,
+    /// which happens when only the member noticed the old one failed.
+    async fn replacement(&mut self) -> S;
+}
+
+enum Redial {
+    /// This node opened the failed connection, so it opens the new one.
+    Dial { handshake: Handshake, address: Address, retry: RetryPolicy, tls: Option<Arc<Tls>> },
+    /// The member opened the failed connection, so this node waits for it to
+    /// open a new one.
+    Await { registry: Reconnections, handler: UnboundedSender<PeerStream>, connections: UnboundedReceiver<PeerStream> }
+}
+
+/// How a member handler gets a new connection to its member when the old one
+/// fails. The member is only given up on if no new connection is made within
+/// `window`.
+pub(crate) struct Reconnect {
+    node_id: NodeId,
+    window: Duration,
+    redial: Redial
+}
+
+/// The reconnection window for a connection to the member that sent
+/// `theirs`, if both sides enabled reconnection.
+fn window(theirs: &Handshake, options: &Options) -> Option<Duration> {
+    options.reconnect_window.filter(|_| theirs.reconnect)
+}
+
+impl Reconnect {
+    /// Reconnect to the member that answered `ours` with `theirs` by dialing
+    /// it again.
+    pub fn dial(theirs: &Handshake, options: &Options, ours: &Handshake, address: Address, tls: Option<Arc<Tls>>) 
+            -> Option<Self> {
+        let handshake = Handshake { resuming: true, ..ours.clone() };
+        let window = window(theirs, options)?;
+        Some(Self {
+            node_id: theirs.node_id,
+            window,
+            redial: Redial::Dial { handshake, address, retry: RetryPolicy::new(options).within(window), tls }
+        })
+    }
+
+    /// Reconnect to the member that dialed this node with `theirs` by waiting
+    /// for it to dial again.
+    pub fn wait_for(theirs: &Handshake, options: &Options, registry: &Reconnections) -> Option<Self> {
+        let window = window(theirs, options)?;
+        let (handler, connections) = unbounded_channel();
+        registry.waiting.lock().unwrap().insert(theirs.node_id, handler.clone());
+        Some(Self {
+            node_id: theirs.node_id,
+            window,
+            redial: Redial::Await { registry: registry.clone(), handler, connections }
+        })
+    }
+}
+
+#[async_trait]
+impl Reconnector<PeerStream> for Reconnect {
+    async fn reconnect(&mut self) -> Option<PeerStream> {
+        let node_id = self.node_id;
+        match &mut self.redial {
+            Redial::Dial { handshake, address, retry, tls } => {
+                // Each round dials until the member answers or the policy gives
+                // up, and the member may still turn this node away after
+                // answering, so rounds are retried until the window closes
+                let mut delays = retry.delays();
+                let redial = async {
+                    loop {
+                        let connected = connect_to_node(handshake.clone(), node_id, address.clone(), retry.clone(), 
+                            tls.clone()).await;
+                        match connected {
+                            Ok((stream, _)) => return stream,
+                            Err(e) => trace!("Could not reconnect to node {}: {}", node_id, e)
+                        }
+                        time::sleep(delays.next().unwrap_or_default()).await;
+                    }
+                };
+                time::timeout(self.window, redial).await.ok()
+            },
+            Redial::Await { connections, .. } => time::timeout(self.window, connections.recv()).await.ok().flatten()
+        }
+    }
+
+    async fn replacement(&mut self) -> PeerStream {
+        if let Redial::Await { connections, .. } = &mut self.redial {
+            if let Some(stream) = connections.recv().await {
+                return stream;
+            }
+        }
+        std::future::pending().await
+    }
+}
+
+impl Drop for Reconnect {
+    fn drop(&mut self) {
+        if let Redial::Await { registry, handler, .. } = &self.redial {
+            let mut waiting = registry.waiting.lock().unwrap();
+            // A handler for a later connection from the member may have
+            // taken this one's place
+            if waiting.get(&self.node_id).is_some_and(|waiting| waiting.same_channel(handler)) {
+                waiting.remove(&self.node_id);
+            }
+        }
+    }
+}
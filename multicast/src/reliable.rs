@@ -1,126 +1,718 @@
 use super::{
-    member::MemberStateMessageType, IncomingChannel, Multicast, MulticastError,
-    config::{Config, NodeId}, basic::BasicMulticast, MulticastGroup
+    member::{MemberStateMessageType, MulticastMemberHandle}, IncomingChannel, Multicast, MulticastError, ConnectError,
+    config::{Config, NodeId}, basic::BasicMulticast, MulticastGroup,
+    connection_pool::{ConnectionPool, JoinListener}, builder::MulticastBuilder, channel::FlowControl, builder::Options,
+    stats::{StatsRegistry, ConnectionStats}, dissemination::Dissemination, codec::nested
 };
-use std::collections::{HashSet, HashMap};
+use tokio_util::bytes::Bytes;
+use std::{collections::{BTreeMap, HashSet, HashMap, VecDeque}, time::Duration};
+use tokio::{select, time::{self, Interval, MissedTickBehavior}};
 use serde::{Deserialize, Serialize, de::DeserializeOwned};
 use async_trait::async_trait;
-use log::trace;
+use log::{trace, error};
+
+/// How many messages from members this node delivers between telling them
+/// how far it has got, which lets them discard messages everyone has.
+static ACK_INTERVAL: usize = 64;
+/// How often this node tells members how far it has got if it delivered any
+/// messages since it last did, so that a lost final message is soon noticed.
+static ACK_PERIOD: Duration = Duration::from_secs(1);
+
+/// A message that is still encoded, the frame it arrived in, and the member
+/// it arrived from.
+type HeldBack = (Vec<u8>, Bytes, NodeId);
 
 /// A reliable multicast implementation that guarantees delivery to all 
 /// members of the group if a message is delivered to at least one member.
+/// 
+/// Messages are forwarded on behalf of their original sender as part of 
+/// `deliver`, so a standalone client must keep calling `deliver` for other 
+/// members to benefit from this node's forwarding.
+///
+/// Messages from each sender are delivered in the order they were sent. One
+/// that arrives before an earlier message from the same sender is held back,
+/// and the member it came from is asked to send the missing ones again.
 pub struct ReliableMulticast<M> {
+    node_id: NodeId,
     /// The underlying basic multicast protocol
     basic: BasicMulticast<ReliableNetworkMessage<M>>,
-    prior_seq: HashMap<NodeId, usize>,
+    /// The sequence number of the next message to deliver from each sender. A
+    /// sender missing from it has whichever message arrives first delivered.
+    next_expected: HashMap<NodeId, usize>,
+    /// Senders whose first message to this node is not known yet, since it
+    /// joined a running group. Their messages are held back until their first
+    /// acknowledgement says where they start.
+    unanchored: HashSet<NodeId>,
+    /// Senders that failed before saying where they start, whose held back
+    /// messages `deliver` still has to release
+    unreleased: Vec<NodeId>,
+    /// Messages that arrived before an earlier one from the same sender, still
+    /// encoded, and the member each came from
+    held_back: HashMap<NodeId, BTreeMap<usize, HeldBack>>,
+    /// For each sender, the end of the last range of missing messages asked
+    /// for, and the member that was asked
+    requested: HashMap<NodeId, (usize, NodeId)>,
+    /// For each sender, the members that no longer had the missing messages
+    /// they were asked for
+    unavailable: HashMap<NodeId, HashSet<NodeId>>,
+    /// Messages that are ready to be returned by `deliver`
+    ready: VecDeque<M>,
+    retransmit: RetransmitBuffer,
+    /// How far each member has acknowledged delivering from each sender
+    acked: HashMap<NodeId, HashMap<NodeId, usize>>,
+    delivered_since_ack: usize,
+    ack_timer: Interval,
+    dissemination: Dissemination,
     next_seq_num: usize
 }
 
 #[derive(Debug, Serialize, Deserialize, Clone)]
-pub struct ReliableNetworkMessage<M> {
-    /// The message to reliably multicast to all other members of the group.
-    pub msg: M,
-    /// If `sequence_num` is `Some(_)`, where `_` is the sequence number, then
-    /// this message must be reliably delivered. Otherwise, this message is a 
-    /// one-off message to a single recipient and may be dropped if the sender
-    /// crashes before transmitting all bytes over the network.
-    pub sequence_num: Option<usize>,
-    /// If this message was forwarded on behalf of some original sender, then 
-    /// `forwarded_for` is `Some(_)` where `_` is the `NodeId` of the original 
-    /// sender. Otherwise, this message originated from the connection it was 
-    /// received on.
-    pub forwarded_for: Option<NodeId>
+pub enum ReliableNetworkMessage<M> {
+    /// A one-off message to a single recipient, which may be dropped if the
+    /// sender crashes before transmitting all bytes over the network.
+    Direct(M),
+    /// A message to reliably multicast to all other members of the group,
+    /// numbered `sequence_num` among those sent by `origin`, the node that
+    /// first sent it. Forwarded messages are passed on unchanged, so this
+    /// stays the same however many hops it takes.
+    ///
+    /// `msg` is the message encoded on its own, so that copies of it that
+    /// were already delivered are skipped without decoding it.
+    Multicast { #[serde(with = "nested")] msg: Vec<u8>, sequence_num: usize, origin: NodeId },
+    /// Asks the recipient to send `origin`'s messages numbered `from` up to,
+    /// but not including, `to` again.
+    Nack { origin: NodeId, from: usize, to: usize },
+    /// Answers a `Nack`: the sender cannot send `origin`'s messages numbered
+    /// `from` up to, but not including, `to`. It either discarded them after
+    /// delivering them, or has not received them yet.
+    Missing { origin: NodeId, from: usize, to: usize, discarded: bool },
+    /// The sequence number of the next message the sender will deliver from
+    /// each origin.
+    Ack(Vec<(NodeId, usize)>)
+}
+
+/// The most recent messages delivered from each sender, kept to be sent again
+/// to members that missed them.
+struct RetransmitBuffer {
+    /// For each sender, consecutive frames starting from the sequence number
+    /// paired with them
+    frames: HashMap<NodeId, (usize, VecDeque<Bytes>)>,
+    capacity: usize
+}
+
+impl RetransmitBuffer {
+    fn new(capacity: usize) -> Self {
+        Self { frames: HashMap::new(), capacity }
+    }
+
+    /// Keep `frame`, which must be the message after the last one kept from
+    /// `origin`, dropping the oldest message if the buffer is full.
+    fn push(&mut self, origin: NodeId, sequence_num: usize, frame: Bytes) {
+        let (first, frames) = self.frames.entry(origin).or_insert_with(|| (sequence_num, VecDeque::new()));
+        frames.push_back(frame);
+        if frames.len() > self.capacity {
+            frames.pop_front();
+            *first += 1;
+        }
+    }
+
+    fn get(&self, origin: NodeId, sequence_num: usize) -> Option<&Bytes> {
+        let (first, frames) = self.frames.get(&origin)?;
+        frames.get(sequence_num.checked_sub(*first)?)
+    }
+
+    /// Drop the messages from `origin` numbered below `stable`.
+    fn discard_below(&mut self, origin: NodeId, stable: usize) {
+        if let Some((first, frames)) = self.frames.get_mut(&origin) {
+            while *first < stable && frames.pop_front().is_some() {
+                *first += 1;
+            }
+        }
+    }
+
+    /// The buffered messages from `origin`, oldest first.
+    fn frames_from(&self, origin: NodeId) -> impl Iterator<Item = (usize, &Bytes)> {
+        self.frames.get(&origin).into_iter().flat_map(|(first, frames)| (*first..).zip(frames))
+    }
+
+    fn forget(&mut self, origin: NodeId) {
+        self.frames.remove(&origin);
+    }
 }
 
 impl<M> ReliableMulticast<M> {
-    pub(crate) fn new(group: MulticastGroup, from_members: IncomingChannel<ReliableNetworkMessage<M>>) -> Self {
+    pub(crate) fn new(node_id: NodeId, group: MulticastGroup, from_members: IncomingChannel<ReliableNetworkMessage<M>>, 
+            flow: FlowControl, stats: StatsRegistry, options: &Options, listener: Option<JoinListener>) -> Self {
         Self { 
-            basic: BasicMulticast::new(group, from_members),
-            prior_seq: HashMap::new(),
+            node_id,
+            next_expected: group.keys().map(|member_id| (*member_id, 0)).collect(),
+            unanchored: HashSet::new(),
+            unreleased: Vec::new(),
+            basic: BasicMulticast::new(group, from_members, flow, stats, options, listener),
+            held_back: HashMap::new(),
+            requested: HashMap::new(),
+            unavailable: HashMap::new(),
+            ready: VecDeque::new(),
+            retransmit: RetransmitBuffer::new(options.retransmit_buffer),
+            acked: HashMap::new(),
+            delivered_since_ack: 0,
+            ack_timer: {
+                let mut timer = time::interval(ACK_PERIOD);
+                timer.set_missed_tick_behavior(MissedTickBehavior::Delay);
+                timer
+            },
+            dissemination: options.dissemination,
             next_seq_num: 0
         }
     }
 
-    fn get_next_seq_num(&mut self) -> Option<usize> {
-        let id = self.next_seq_num;
-        self.next_seq_num += 1;
-        Some(id)
+    pub(crate) async fn connect_with(node_id: NodeId, config: Config) -> Result<Self, ConnectError> 
+            where M: 'static + Send + Serialize + DeserializeOwned {
+        let mut pool = ConnectionPool::new(node_id, &config.options)
+            .connect(&config)
+            .await?;
+
+        trace!("finished connecting to group!");
+
+        let listener = pool.listen_for_reconnections(&config);
+        Ok(ReliableMulticast::new(node_id, pool.group, pool.from_members, pool.flow, pool.stats, &config.options, listener))
+    }
+
+    /// Deliver each member's messages from the first one it sent after this
+    /// node connected rather than from its first ever. Used by a node joining
+    /// a running group.
+    pub(crate) fn start_mid_stream(&mut self) {
+        self.unanchored = self.next_expected.drain().map(|(member_id, _)| member_id).collect();
+    }
+
+    /// Add a member that connected after the group was established. Sequence
+    /// numbers restart from zero when a node rejoins, so any history for 
+    /// `handle.member_id` is forgotten.
+    ///
+    /// The member is sent an acknowledgement before anything else, which
+    /// tells it where this node's messages to it start.
+    pub(crate) fn add_member(&mut self, handle: MulticastMemberHandle) where M: Serialize {
+        let member_id = handle.member_id;
+        self.next_expected.insert(member_id, 0);
+        self.unanchored.remove(&member_id);
+        self.held_back.remove(&member_id);
+        self.requested.remove(&member_id);
+        self.unavailable.remove(&member_id);
+        self.retransmit.forget(member_id);
+        self.acked.remove(&member_id);
+        self.basic.add_member(handle);
+
+        if let Err(e) = self.basic.send_unchecked(ReliableNetworkMessage::Ack(self.delivered()), member_id) {
+            trace!("Failed to send acknowledgement to new member {}: {:?}", member_id, e);
+        }
     }
 
-    pub fn remove_member(&mut self, member_id: &NodeId) {
+    /// Remove a member that failed. The rest of the group is told how far
+    /// this node has got, so that messages the member was meant to pass on
+    /// can be asked for from whichever members did receive them. With lazy
+    /// dissemination, the member's own messages are passed on now.
+    pub fn remove_member(&mut self, member_id: &NodeId) where M: Serialize {
+        if !self.members().contains(member_id) {
+            return;
+        }
+
         self.basic.remove_member(member_id);
+        // Messages it never acknowledged no longer need to be kept for it
+        self.acked.remove(member_id);
+        // Without its first acknowledgement, its messages are delivered from
+        // the earliest one that arrived
+        if self.unanchored.remove(member_id) {
+            if let Some(&first) = self.held_back.get(member_id).and_then(|held_back| held_back.keys().next()) {
+                self.next_expected.insert(*member_id, first);
+                self.unreleased.push(*member_id);
+            }
+        }
+        if let Dissemination::Lazy = self.dissemination {
+            self.forward_buffered(*member_id);
+        }
+        self.send_ack();
     }
 
     pub fn members(&self) -> &HashSet<NodeId> {
-        &self.basic.members()
+        self.basic.members()
+    }
+
+    /// Reliably multicast `msg` regardless of how full the outgoing queues
+    /// are. Used for messages the protocols above send on their own.
+    pub(crate) fn broadcast_unchecked(&mut self, msg: M) -> Result<(), MulticastError> where M: Serialize {
+        let sequence_num = self.next_seq_num;
+        let msg = self.basic.encode_nested(&msg)?;
+        let frame = self.basic.encode(&ReliableNetworkMessage::Multicast { msg, sequence_num, origin: self.node_id })?;
+        self.next_seq_num += 1;
+
+        self.retransmit.push(self.node_id, sequence_num, frame.clone());
+        let recipients = self.dissemination.recipients(self.node_id, self.node_id, self.node_id, self.members());
+        self.basic.forward_among(frame, &recipients)
+    }
+
+    pub(crate) fn send_unchecked(&self, msg: M, recipient: NodeId) -> Result<(), MulticastError> where M: Serialize {
+        self.basic.send_unchecked(ReliableNetworkMessage::Direct(msg), recipient)
+    }
+
+    /// What has been sent to each member this node has connected to so far.
+    pub fn connection_stats(&self) -> HashMap<NodeId, ConnectionStats> {
+        self.basic.connection_stats()
+    }
+
+    pub(crate) fn stats(&self) -> &StatsRegistry {
+        self.basic.stats()
+    }
+
+    pub(crate) fn flow(&self) -> &FlowControl {
+        self.basic.flow()
+    }
+
+    pub(crate) fn full_members(&self, recipient: Option<NodeId>) -> Vec<NodeId> {
+        self.basic.full_members(recipient)
+    }
+
+    pub(crate) fn max_round_trip(&self) -> Option<Duration> {
+        self.basic.max_round_trip()
+    }
+
+    /// Handle message number `sequence_num` from `origin`, which arrived from
+    /// `sender`. It is made ready for `deliver` and forwarded to the rest of
+    /// the group if it is the next message from `origin`, along with any held
+    /// back messages that were waiting on it.
+    fn receive(&mut self, sender: NodeId, msg: Vec<u8>, sequence_num: usize, origin: NodeId, frame: Bytes) 
+            -> Result<(), MulticastError> where M: Serialize + DeserializeOwned {
+        if self.unanchored.contains(&origin) {
+            trace!("Holding back message {} from node {}: where its messages start is not known yet", sequence_num, origin);
+            let held_back = self.held_back.entry(origin).or_default();
+            if held_back.len() < self.retransmit.capacity {
+                held_back.insert(sequence_num, (msg, frame, sender));
+            }
+            return Ok(());
+        }
+
+        let next = self.next_expected.get(&origin).copied().unwrap_or(sequence_num);
+        if origin == self.node_id || sequence_num < next {
+            trace!("network message from node {} ... skipping ... next={} and sequence_num={}", sender, next, sequence_num);
+            return Ok(());
+        }
+        if sequence_num > next {
+            trace!("Holding back message {} from node {}: message {} has not arrived", sequence_num, origin, next);
+            let held_back = self.held_back.entry(origin).or_default();
+            if held_back.len() < self.retransmit.capacity {
+                held_back.insert(sequence_num, (msg, frame, sender));
+            }
+            self.request_missing(origin, next, sequence_num, sender);
+            return Ok(());
+        }
+
+        let forwarded = self.accept(sender, msg, sequence_num, origin, frame);
+        forwarded.and(self.release_held_back(origin))
+    }
+
+    /// Accept the messages held back from `origin` that no longer wait on an
+    /// earlier one.
+    fn release_held_back(&mut self, origin: NodeId) -> Result<(), MulticastError> where M: Serialize + DeserializeOwned {
+        let mut forwarded = Ok(());
+        while let Some((msg, frame, sender)) = self.held_back
+                .get_mut(&origin)
+                .and_then(|held_back| held_back.remove(&self.next_expected[&origin])) {
+            let sequence_num = self.next_expected[&origin];
+            forwarded = forwarded.and(self.accept(sender, msg, sequence_num, origin, frame));
+        }
+
+        if self.delivered_since_ack >= ACK_INTERVAL {
+            self.send_ack();
+        }
+        forwarded
+    }
+
+    /// Deliver the next message from `origin` and pass it on to the members
+    /// the dissemination strategy picks. This is the only place a multicast
+    /// message is decoded.
+    fn accept(&mut self, sender: NodeId, msg: Vec<u8>, sequence_num: usize, origin: NodeId, frame: Bytes) 
+            -> Result<(), MulticastError> where M: DeserializeOwned {
+        trace!("network message from node {} ... got message {} from node {}", sender, sequence_num, origin);
+        self.next_expected.insert(origin, sequence_num + 1);
+        self.delivered_since_ack += 1;
+        if self.requested.get(&origin).is_some_and(|(end, _)| sequence_num + 1 >= *end) {
+            self.requested.remove(&origin);
+            self.unavailable.remove(&origin);
+        }
+        // Every member fails to decode it alike, so it still counts as delivered
+        match self.basic.decode_nested(&msg) {
+            Ok(msg) => self.ready.push_back(msg),
+            Err(e) => error!("Skipping message {} from node {}: {}", sequence_num, origin, e)
+        }
+
+        // The frame is forwarded as it arrived, without encoding it again
+        self.retransmit.push(origin, sequence_num, frame.clone());
+        let recipients = self.dissemination.recipients(self.node_id, origin, sender, self.members());
+        self.basic.forward_among(frame, &recipients)
+    }
+
+    /// Ask `member` for `origin`'s messages numbered from `from` up to `to`,
+    /// unless they were already asked for from a member that is still around,
+    /// or `member` already said it does not have them. If `member` cannot be
+    /// asked, another member is asked once it is removed from the group.
+    fn request_missing(&mut self, origin: NodeId, from: usize, to: usize, member: NodeId) where M: Serialize {
+        let from = match self.requested.get(&origin) {
+            Some(&(end, asked)) if self.members().contains(&asked) => from.max(end),
+            _ => from
+        };
+        if from >= to || self.unavailable.get(&origin).is_some_and(|members| members.contains(&member)) {
+            return;
+        }
+
+        trace!("Asking node {} for messages {} to {} from node {}", member, from, to, origin);
+        self.requested.insert(origin, (to, member));
+        if let Err(e) = self.basic.send_unchecked(ReliableNetworkMessage::Nack { origin, from, to }, member) {
+            trace!("Failed to ask node {} for messages from node {}: {:?}", member, origin, e);
+        }
+    }
+
+    /// Send `member` the messages from `origin` numbered from `from` up to
+    /// `to` that are still buffered, and tell it about the ones that are not:
+    /// first those that were discarded, then those not received yet. Gives up
+    /// if `member` can no longer be sent to, which happens once it failed.
+    fn resend(&self, member: NodeId, origin: NodeId, from: usize, to: usize) where M: Serialize {
+        let received = match origin == self.node_id {
+            true => self.next_seq_num,
+            false => self.next_expected.get(&origin).copied().unwrap_or_default()
+        };
+        let mut discarded: Option<(usize, usize)> = None;
+        let mut not_received: Option<(usize, usize)> = None;
+        for sequence_num in from..to {
+            let missing = match self.retransmit.get(origin, sequence_num) {
+                Some(frame) => match self.basic.forward_to(frame.clone(), member) {
+                    Ok(()) => continue,
+                    Err(e) => {
+                        trace!("Failed to send messages from node {} to node {} again: {:?}", origin, member, e);
+                        return;
+                    }
+                },
+                None if sequence_num < received => &mut discarded,
+                None => &mut not_received
+            };
+            *missing = Some(missing.map_or((sequence_num, sequence_num + 1), |(first, _)| (first, sequence_num + 1)));
+        }
+
+        for (range, discarded) in [(discarded, true), (not_received, false)] {
+            if let Some((from, to)) = range {
+                trace!("Cannot send messages {} to {} from node {} to node {} again: they are not buffered", 
+                    from, to - 1, origin, member);
+                if let Err(e) = self.basic.send_unchecked(ReliableNetworkMessage::Missing { origin, from, to, discarded }, member) {
+                    trace!("Failed to tell node {} which messages are not buffered: {:?}", member, e);
+                    return;
+                }
+            }
+        }
+    }
+
+    /// Ask another member for the missing messages from `origin`, since
+    /// `member` does not have those numbered below `to`.
+    ///
+    /// If `member` discarded them, it is not asked for them again, and once
+    /// every member discarded them they are skipped and reported as lost. If
+    /// it has not received them yet, they are asked for again later, when a
+    /// member turns out to have them.
+    fn ask_elsewhere(&mut self, member: NodeId, origin: NodeId, to: usize, discarded: bool) -> Result<(), MulticastError> 
+            where M: Serialize + DeserializeOwned {
+        let next = match self.next_expected.get(&origin) {
+            Some(&next) if next < to => next,
+            _ => return Ok(())
+        };
+        let end = match self.requested.get(&origin) {
+            Some(&(end, asked)) if asked == member => {
+                self.requested.remove(&origin);
+                end.max(to)
+            },
+            _ => to
+        };
+        let unavailable = self.unavailable.entry(origin).or_default();
+        if discarded {
+            unavailable.insert(member);
+        }
+
+        let mut candidates: Vec<NodeId> = self.basic.members()
+            .iter()
+            .cloned()
+            .filter(|candidate| *candidate != member && !unavailable.contains(candidate))
+            .collect();
+        candidates.sort_unstable();
+        if let Some(&candidate) = candidates.first() {
+            self.request_missing(origin, next, end, candidate);
+            return Ok(());
+        }
+        // Members that have not received them yet may still do so
+        let everywhere_discarded = self.basic.members()
+            .iter()
+            .all(|candidate| unavailable.contains(candidate));
+        if !everywhere_discarded {
+            return Ok(());
+        }
+
+        // Every member that could have them was asked
+        error!("Skipping messages {} to {} from node {}: no member has them any more", next, to - 1, origin);
+        self.next_expected.insert(origin, to);
+        self.requested.remove(&origin);
+        self.unavailable.remove(&origin);
+        if let Some(held_back) = self.held_back.get_mut(&origin) {
+            held_back.retain(|sequence_num, _| *sequence_num >= to);
+        }
+        self.release_held_back(origin)?;
+        Err(MulticastError::MessagesLost { origin, from: next, to })
+    }
+
+    /// Pass the buffered messages from `origin` on to every member that has
+    /// not acknowledged them.
+    fn forward_buffered(&self, origin: NodeId) {
+        for (sequence_num, frame) in self.retransmit.frames_from(origin) {
+            let recipients: Vec<NodeId> = self.members()
+                .iter()
+                .cloned()
+                .filter(|member| self.acked.get(member).and_then(|acked| acked.get(&origin)).is_none_or(|next| *next <= sequence_num))
+                .collect();
+            trace!("Passing message {} from node {} on to nodes {:?}", sequence_num, origin, recipients);
+            if let Err(e) = self.basic.forward_among(frame.clone(), &recipients) {
+                trace!("Failed to pass on message {} from node {}: {:?}", sequence_num, origin, e);
+            }
+        }
+    }
+
+    /// How far this node has delivered from each origin, itself included.
+    fn delivered(&self) -> Vec<(NodeId, usize)> {
+        self.next_expected
+            .iter()
+            .map(|(origin, next)| (*origin, *next))
+            .chain([(self.node_id, self.next_seq_num)])
+            .collect()
+    }
+
+    /// Tell every member how far this node has delivered from each origin.
+    fn send_ack(&mut self) where M: Serialize {
+        self.delivered_since_ack = 0;
+        if let Err(e) = self.basic.broadcast_except(ReliableNetworkMessage::Ack(self.delivered()), Vec::new()) {
+            trace!("Failed to send acknowledgement: {:?}", e);
+        }
+        self.discard_stable();
+    }
+
+    /// Record how far `member` has delivered, and ask it for any messages it
+    /// has delivered that this node has not received. An origin this node has
+    /// delivered nothing from counts as delivered up to where its messages to
+    /// this node start.
+    fn record_ack(&mut self, member: NodeId, delivered: Vec<(NodeId, usize)>) -> Result<(), MulticastError> 
+            where M: Serialize + DeserializeOwned {
+        let node_id = self.node_id;
+        let mut released = Ok(());
+        for &(origin, next) in delivered.iter().filter(|(origin, _)| *origin != node_id) {
+            if self.unanchored.contains(&origin) {
+                // Only the origin's own first acknowledgement is known to come
+                // before its messages to this node
+                if origin == member {
+                    released = released.and(self.anchor(origin, next));
+                }
+                continue;
+            }
+            let ours = match self.next_expected.get(&origin) {
+                Some(&ours) => ours,
+                None if self.members().contains(&origin) => 0,
+                None => continue
+            };
+            if next > ours {
+                self.request_missing(origin, ours, next, member);
+            }
+        }
+
+        self.acked.insert(member, delivered.into_iter().collect());
+        self.discard_stable();
+        released
+    }
+
+    /// Start delivering `origin`'s messages from number `start`, the first one
+    /// it sent after this node connected. Held back messages from before then
+    /// are dropped, and any between `start` and the rest asked for.
+    fn anchor(&mut self, origin: NodeId, start: usize) -> Result<(), MulticastError> where M: Serialize + DeserializeOwned {
+        trace!("Messages from node {} to this node start at {}", origin, start);
+        self.unanchored.remove(&origin);
+        self.next_expected.insert(origin, start);
+        if let Some(held_back) = self.held_back.get_mut(&origin) {
+            held_back.retain(|sequence_num, _| *sequence_num >= start);
+        }
+        let released = self.release_held_back(origin);
+
+        let next = self.next_expected[&origin];
+        let waiting = self.held_back.get(&origin).and_then(|held_back| held_back.iter().next());
+        if let Some((&first, &(_, _, sender))) = waiting {
+            self.request_missing(origin, next, first, sender);
+        }
+        released
+    }
+
+    /// Discard the buffered messages that every member has delivered.
+    fn discard_stable(&mut self) {
+        let origins: Vec<NodeId> = self.retransmit.frames.keys().cloned().collect();
+        for origin in origins {
+            let stable = self.basic.members()
+                .iter()
+                .filter(|member| **member != origin)
+                .map(|member| self.acked.get(member).and_then(|acked| acked.get(&origin)).copied().unwrap_or_default())
+                .min();
+            if let Some(stable) = stable {
+                self.retransmit.discard_below(origin, stable);
+            }
+        }
     }
 }
 
 #[async_trait]
-impl<M> Multicast<M> for ReliableMulticast<M> where M: Send + Serialize {
-    async fn connect(_: usize, _: Config, _: u64) -> Self where M: 'static + DeserializeOwned { todo!() }
+impl<M> Multicast<M> for ReliableMulticast<M> where M: Send + Serialize + DeserializeOwned {
+    async fn connect(node_id: NodeId, config: Config, timeout_secs: u64) -> Result<Self, ConnectError> where M: 'static + DeserializeOwned {
+        MulticastBuilder::new(node_id, config)
+            .connect_timeout(Duration::from_secs(timeout_secs))
+            .reliable()
+            .await
+    }
 
     async fn broadcast(&mut self, msg: M) -> Result<(), MulticastError> { 
-        let sequence_num = self.get_next_seq_num();
-        self.basic.broadcast(ReliableNetworkMessage {
-            msg,
-            forwarded_for: None,
-            sequence_num
-        }).await
+        self.basic.admit(None).await?;
+        self.broadcast_unchecked(msg)
     }
 
     async fn send_to(&mut self, msg: M, recipient: NodeId) -> Result<(), MulticastError> { 
-        self.basic.send_to(
-            ReliableNetworkMessage { 
-                msg, sequence_num: None, forwarded_for: None
-            }, 
-            recipient
-        ).await
+        if !self.members().contains(&recipient) {
+            return Err(MulticastError::InvalidRecipient(recipient));
+        }
+        self.basic.admit(Some(recipient)).await?;
+        self.send_unchecked(msg, recipient)
     }
 
     async fn deliver(&mut self) -> Result<M, MulticastError> { 
-        let member_state = match self.basic.raw_deliver().await {
-            Some(s) => s,
-            None => return Err(MulticastError::AllClientsDisconnected)
-        };
+        loop {
+            if let Some(msg) = self.ready.pop_front() {
+                return Ok(msg);
+            }
+            if let Some(origin) = self.unreleased.pop() {
+                self.release_held_back(origin)?;
+                continue;
+            }
 
-        match member_state.msg {
-            MemberStateMessageType::Message(mut msg) => {
-                if msg.sequence_num.is_none() {
-                    trace!("network message from node {} ... one off message", member_state.member_id);
-                    
-                    return Ok(msg.msg);
-                }
-    
-                let msg_seq_num = msg.sequence_num.unwrap();
-    
-                let mut except = vec![member_state.member_id];
-                let original_sender = match msg.forwarded_for {
-                    Some(original_sender) => {
-                        except.push(original_sender);
-                        original_sender
-                    },
-                    None => member_state.member_id
-                };
-    
-                if let Some(last_seq) = self.prior_seq.get(&original_sender) {
-                    if last_seq >= &msg_seq_num {
-                        trace!("network message from node {} ... skipping ... last_seq={} and msg.sequence_num={}", member_state.member_id, last_seq, msg_seq_num);
-                        
-                        // got a duplicated message, skip and wait --> recurse!
-                        return self.deliver().await; 
+            let member_state = select! {
+                member_state = self.basic.raw_deliver() => match member_state {
+                    Some(s) => s,
+                    None => return Err(MulticastError::AllClientsDisconnected)
+                },
+                _ = self.ack_timer.tick() => {
+                    if self.delivered_since_ack > 0 {
+                        self.send_ack();
                     }
+                    continue
                 }
-    
-                trace!("network message from node {} ... got ReliableNetworkMessage {{ msg: (...), sequence_num: {:?}, forwarded_for: {:?}}}", member_state.member_id, msg.sequence_num, msg.forwarded_for);
-    
-                self.prior_seq.insert(original_sender, msg_seq_num);
-                msg.forwarded_for = Some(original_sender);
-                self.basic
-                    .broadcast_except(msg, except)
-                    .map(|m| m.msg)
-            },
-            MemberStateMessageType::NetworkError => Err(MulticastError::ClientDisconnected(member_state.member_id))
+            };
+            let sender = member_state.member_id;
+
+            match member_state.msg {
+                MemberStateMessageType::Message(msg, frame) => match msg {
+                    ReliableNetworkMessage::Direct(msg) => {
+                        trace!("network message from node {} ... one off message", sender);
+                        return Ok(msg);
+                    },
+                    ReliableNetworkMessage::Multicast { msg, sequence_num, origin } => 
+                        self.receive(sender, msg, sequence_num, origin, frame)?,
+                    ReliableNetworkMessage::Nack { origin, from, to } => self.resend(sender, origin, from, to),
+                    ReliableNetworkMessage::Missing { origin, to, discarded, .. } => 
+                        self.ask_elsewhere(sender, origin, to, discarded)?,
+                    ReliableNetworkMessage::Ack(delivered) => self.record_ack(sender, delivered)?
+                },
+                MemberStateMessageType::NetworkError => {
+                    self.remove_member(&sender);
+                    return Err(MulticastError::ClientDisconnected(sender));
+                },
+                MemberStateMessageType::Suspected => return Err(MulticastError::ClientSuspected(sender)),
+                MemberStateMessageType::Alive => return Err(MulticastError::ClientRecovered(sender))
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn frame(sequence_num: usize) -> Bytes {
+        Bytes::from(sequence_num.to_string())
+    }
+
+    /// A buffer of `capacity` holding `origin`'s messages numbered `from` up
+    /// to, but not including, `to`.
+    fn buffer(capacity: usize, origin: NodeId, from: usize, to: usize) -> RetransmitBuffer {
+        let mut buffer = RetransmitBuffer::new(capacity);
+        for sequence_num in from..to {
+            buffer.push(origin, sequence_num, frame(sequence_num));
         }
+        buffer
+    }
+
+    fn kept(buffer: &RetransmitBuffer, origin: NodeId) -> Vec<usize> {
+        buffer.frames_from(origin).map(|(sequence_num, _)| sequence_num).collect()
+    }
+
+    #[test]
+    fn get_within_capacity() {
+        let buffer = buffer(4, 1, 0, 3);
+        for sequence_num in 0..3 {
+            assert_eq!(buffer.get(1, sequence_num), Some(&frame(sequence_num)));
+        }
+        assert_eq!(buffer.get(1, 3), None);
+        assert_eq!(buffer.get(0, 0), None);
+    }
+
+    #[test]
+    fn get_across_capacity() {
+        let buffer = buffer(3, 1, 0, 10);
+        assert_eq!(kept(&buffer, 1), [7, 8, 9]);
+        assert_eq!(buffer.get(1, 6), None);
+        assert_eq!(buffer.get(1, 7), Some(&frame(7)));
+        assert_eq!(buffer.get(1, 9), Some(&frame(9)));
+        assert_eq!(buffer.get(1, 10), None);
+    }
+
+    #[test]
+    fn starts_from_first_pushed() {
+        // The first message kept need not be numbered 0
+        let buffer = buffer(8, 2, 40, 43);
+        assert_eq!(kept(&buffer, 2), [40, 41, 42]);
+        assert_eq!(buffer.get(2, 0), None);
+        assert_eq!(buffer.get(2, 41), Some(&frame(41)));
+    }
+
+    #[test]
+    fn discard_below() {
+        let mut buffer = buffer(4, 1, 0, 6);
+        buffer.discard_below(1, 3);
+        assert_eq!(kept(&buffer, 1), [3, 4, 5]);
+        assert_eq!(buffer.get(1, 2), None);
+        assert_eq!(buffer.get(1, 3), Some(&frame(3)));
+
+        // Below what was already dropped, nothing changes
+        buffer.discard_below(1, 1);
+        assert_eq!(kept(&buffer, 1), [3, 4, 5]);
+
+        buffer.push(1, 6, frame(6));
+        assert_eq!(buffer.get(1, 6), Some(&frame(6)));
+        buffer.discard_below(1, 100);
+        assert!(kept(&buffer, 1).is_empty());
     }
-}
\ No newline at end of file
+
+    #[test]
+    fn origins_are_kept_apart() {
+        let mut buffer = buffer(2, 1, 0, 3);
+        buffer.push(2, 0, frame(100));
+        buffer.discard_below(2, 1);
+        assert_eq!(kept(&buffer, 1), [1, 2]);
+        assert!(kept(&buffer, 2).is_empty());
+
+        buffer.forget(1);
+        assert_eq!(buffer.get(1, 2), None);
+    }
+}
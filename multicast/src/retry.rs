@@ -0,0 +1,200 @@
+use super::{NodeId, Address, builder::Options, transport::Stream};
+use tokio::{sync::mpsc::UnboundedSender, time::{self, Instant}};
+use rand::Rng;
+use std::{io, time::Duration};
+use log::trace;
+
+/// How long to wait between attempts to connect to a member.
+#[derive(Clone, Copy, Debug)]
+pub enum RetryStrategy {
+    /// Wait the same amount of time between every attempt.
+    Fixed(Duration),
+    /// Double the wait after every attempt, starting from `initial` and never
+    /// waiting longer than `max`.
+    Exponential { initial: Duration, max: Duration },
+    /// Like `Exponential`, but each wait is picked at random between half and
+    /// all of the exponential wait, so that nodes that lost a member at the
+    /// same time do not all dial it at the same moments.
+    ExponentialJitter { initial: Duration, max: Duration }
+}
+
+impl RetryStrategy {
+    /// The waits between consecutive attempts.
+    pub(crate) fn delays(&self) -> Box<dyn Iterator<Item = Duration> + Send> {
+        match *self {
+            RetryStrategy::Fixed(delay) => Box::new(std::iter::repeat(delay)),
+            RetryStrategy::Exponential { initial, max } =>
+                Box::new(std::iter::successors(Some(initial.min(max)), move |d| Some(d.saturating_mul(2).min(max)))),
+            RetryStrategy::ExponentialJitter { initial, max } => Box::new(
+                RetryStrategy::Exponential { initial, max }
+                    .delays()
+                    .map(|d| d / 2 + d.mul_f64(rand::thread_rng().gen_range(0.0..=0.5)))
+            )
+        }
+    }
+}
+
+/// What happened on an attempt to connect to a member, as reported to
+/// `MulticastBuilder::connect_events`.
+#[derive(Clone, Debug)]
+pub enum ConnectEvent {
+    /// Attempt number `attempt` to reach `node_id` failed. It is tried again
+    /// after `retry_in`, or given up on if that is `None`.
+    AttemptFailed { node_id: NodeId, address: Address, attempt: usize, error: String, retry_in: Option<Duration> },
+    /// `node_id` was reached on attempt number `attempt`, `elapsed` after the
+    /// first attempt started.
+    Connected { node_id: NodeId, address: Address, attempt: usize, elapsed: Duration }
+}
+
+/// How a member is dialed: how long to wait between attempts, when to give
+/// up, and who to tell about each attempt.
+#[derive(Clone)]
+pub(crate) struct RetryPolicy {
+    strategy: RetryStrategy,
+    max_attempts: Option<usize>,
+    deadline: Option<Duration>,
+    events: Option<UnboundedSender<ConnectEvent>>
+}
+
+impl RetryPolicy {
+    pub fn new(options: &Options) -> Self {
+        Self {
+            strategy: options.retry_strategy,
+            max_attempts: options.max_connect_attempts,
+            deadline: options.peer_connect_deadline,
+            events: options.connect_events.clone()
+        }
+    }
+
+    /// Give up after `attempts` unless an earlier limit was set.
+    pub fn at_most(mut self, attempts: usize) -> Self {
+        self.max_attempts = Some(self.max_attempts.map_or(attempts, |max| max.min(attempts)));
+        self
+    }
+
+    /// Give up once `limit` has passed unless an earlier deadline was set.
+    pub fn within(mut self, limit: Duration) -> Self {
+        self.deadline = Some(self.deadline.map_or(limit, |deadline| deadline.min(limit)));
+        self
+    }
+
+    /// The waits between consecutive attempts.
+    pub fn delays(&self) -> Box<dyn Iterator<Item = Duration> + Send> {
+        self.strategy.delays()
+    }
+
+    fn report(&self, event: ConnectEvent) {
+        if let Some(events) = &self.events {
+            let _ = events.send(event);
+        }
+    }
+
+    /// Dial `node_id` at `address` until it answers or this policy gives up
+    /// on it, in which case the last attempt's error is returned.
+    pub async fn dial(&self, node_id: NodeId, address: &Address) -> io::Result<Stream> {
+        let started = Instant::now();
+        let deadline = self.deadline.map(|deadline| started + deadline);
+        let mut delays = self.strategy.delays();
+        let mut attempt = 0;
+
+        loop {
+            attempt += 1;
+            // An attempt to reach a host that is down can take minutes to fail
+            let connected = match deadline {
+                Some(deadline) => time::timeout_at(deadline, address.connect()).await
+                    .unwrap_or_else(|_| Err(io::Error::new(io::ErrorKind::TimedOut, "connect timed out"))),
+                None => address.connect().await
+            };
+            let error = match connected {
+                Ok(stream) => {
+                    trace!("Reached node {} at {} on attempt {}", node_id, address, attempt);
+                    self.report(ConnectEvent::Connected { node_id, address: address.clone(), attempt, elapsed: started.elapsed() });
+                    return Ok(stream);
+                },
+                Err(e) => e
+            };
+
+            let delay = delays.next().unwrap_or_default();
+            let out_of_attempts = self.max_attempts.is_some_and(|max| attempt >= max);
+            let out_of_time = deadline.is_some_and(|deadline| Instant::now() + delay >= deadline);
+            let retry_in = (!out_of_attempts && !out_of_time).then_some(delay);
+            trace!("Attempt {} to reach node {} at {} failed: {} (retrying in {:?})", attempt, node_id, address, error, retry_in);
+            self.report(ConnectEvent::AttemptFailed {
+                node_id,
+                address: address.clone(),
+                attempt,
+                error: error.to_string(),
+                retry_in
+            });
+
+            match retry_in {
+                Some(delay) => time::sleep(delay).await,
+                None => return Err(io::Error::new(error.kind(), format!("gave up after {} attempts: {}", attempt, error)))
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn ms(millis: u64) -> Duration {
+        Duration::from_millis(millis)
+    }
+
+    #[test]
+    fn fixed_delays() {
+        let delays: Vec<_> = RetryStrategy::Fixed(ms(100)).delays().take(3).collect();
+        assert_eq!(delays, [ms(100); 3]);
+    }
+
+    #[test]
+    fn exponential_delays() {
+        let delays: Vec<_> = RetryStrategy::Exponential { initial: ms(100), max: ms(1000) }.delays().take(6).collect();
+        assert_eq!(delays, [ms(100), ms(200), ms(400), ms(800), ms(1000), ms(1000)]);
+
+        let capped: Vec<_> = RetryStrategy::Exponential { initial: ms(500), max: ms(200) }.delays().take(2).collect();
+        assert_eq!(capped, [ms(200); 2]);
+    }
+
+    #[test]
+    fn exponential_delays_saturate() {
+        let strategy = RetryStrategy::Exponential { initial: ms(1), max: Duration::MAX };
+        assert_eq!(strategy.delays().nth(200), Some(Duration::MAX));
+    }
+
+    #[test]
+    fn jittered_delays() {
+        let exponential = RetryStrategy::Exponential { initial: ms(100), max: ms(1000) }.delays();
+        let jittered = RetryStrategy::ExponentialJitter { initial: ms(100), max: ms(1000) }.delays();
+        for (delay, jittered) in exponential.zip(jittered).take(20) {
+            assert!(delay / 2 <= jittered && jittered <= delay, "{:?} is not within half of {:?}", jittered, delay);
+        }
+    }
+
+    #[tokio::test]
+    async fn gives_up_after_max_attempts() {
+        // Nothing listens on a port once its listener is dropped
+        let address = {
+            let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+            Address::Tcp { host: "127.0.0.1".into(), port: listener.local_addr().unwrap().port() }
+        };
+        let (events, mut received) = tokio::sync::mpsc::unbounded_channel();
+        let options = Options {
+            retry_strategy: RetryStrategy::Fixed(ms(1)),
+            connect_events: Some(events),
+            ..Default::default()
+        };
+
+        let error = RetryPolicy::new(&options).at_most(3).dial(4, &address).await.err().unwrap();
+        assert!(error.to_string().starts_with("gave up after 3 attempts"), "{}", error);
+        let retries: Vec<_> = std::iter::from_fn(|| received.try_recv().ok())
+            .map(|event| match event {
+                ConnectEvent::AttemptFailed { node_id: 4, retry_in, .. } => retry_in,
+                event => panic!("unexpected {:?}", event)
+            })
+            .collect();
+        assert_eq!(retries, [Some(ms(1)), Some(ms(1)), None]);
+    }
+}
@@ -0,0 +1,98 @@
+use super::NodeId;
+use std::{collections::HashMap, sync::{Arc, Mutex, atomic::{AtomicU64, Ordering}}};
+
+/// The traffic counters a member handler updates for its connection.
+#[derive(Default, Debug)]
+pub(crate) struct Counters {
+    frames_written: AtomicU64,
+    writes: AtomicU64,
+    bytes_written: AtomicU64,
+    uncompressed_bytes: AtomicU64,
+    compressed_bytes: AtomicU64
+}
+
+impl Counters {
+    /// Count one write to the socket that carried `frames` frames.
+    pub fn record_write(&self, frames: u64, bytes: u64) {
+        self.frames_written.fetch_add(frames, Ordering::Relaxed);
+        self.writes.fetch_add(1, Ordering::Relaxed);
+        self.bytes_written.fetch_add(bytes, Ordering::Relaxed);
+    }
+
+    /// Count a message that was `uncompressed` bytes once encoded and
+    /// `compressed` bytes as written, which is the same if it was sent as is.
+    pub fn record_payload(&self, uncompressed: u64, compressed: u64) {
+        self.uncompressed_bytes.fetch_add(uncompressed, Ordering::Relaxed);
+        self.compressed_bytes.fetch_add(compressed, Ordering::Relaxed);
+    }
+
+    fn snapshot(&self) -> ConnectionStats {
+        ConnectionStats {
+            frames_written: self.frames_written.load(Ordering::Relaxed),
+            writes: self.writes.load(Ordering::Relaxed),
+            bytes_written: self.bytes_written.load(Ordering::Relaxed),
+            uncompressed_bytes: self.uncompressed_bytes.load(Ordering::Relaxed),
+            compressed_bytes: self.compressed_bytes.load(Ordering::Relaxed)
+        }
+    }
+}
+
+/// What this node has sent over its connection to one member.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct ConnectionStats {
+    /// Messages and heartbeats written.
+    pub frames_written: u64,
+    /// Flushes to the socket. Each one carries a batch of frames.
+    pub writes: u64,
+    /// Bytes written, including frame headers.
+    pub bytes_written: u64,
+    /// The size of the messages written, before compression.
+    pub uncompressed_bytes: u64,
+    /// The size of the same messages as written, after compression where it
+    /// was used.
+    pub compressed_bytes: u64
+}
+
+impl ConnectionStats {
+    /// The average number of frames per write, which is 1 without batching.
+    pub fn mean_batch_size(&self) -> f64 {
+        match self.writes {
+            0 => 0.0,
+            writes => self.frames_written as f64 / writes as f64
+        }
+    }
+
+    /// How much smaller compression made the messages, which is 1 without
+    /// compression.
+    pub fn compression_ratio(&self) -> f64 {
+        match self.compressed_bytes {
+            0 => 1.0,
+            compressed => self.uncompressed_bytes as f64 / compressed as f64
+        }
+    }
+}
+
+/// The counters of every connection opened by one multicast group.
+#[derive(Clone, Default)]
+pub(crate) struct StatsRegistry {
+    connections: Arc<Mutex<HashMap<NodeId, Arc<Counters>>>>
+}
+
+impl StatsRegistry {
+    /// Fresh counters for a new connection to `member_id`, replacing those of
+    /// any earlier connection to it.
+    pub fn register(&self, member_id: NodeId) -> Arc<Counters> {
+        let counters: Arc<Counters> = Default::default();
+        self.connections.lock().unwrap().insert(member_id, counters.clone());
+        counters
+    }
+
+    pub fn snapshot(&self) -> HashMap<NodeId, ConnectionStats> {
+        self.connections
+            .lock()
+            .unwrap()
+            .iter()
+            .map(|(id, counters)| (*id, counters.snapshot()))
+            .collect()
+    }
+}
@@ -0,0 +1,232 @@
+use super::{Config, NodeId, Address, transport::Stream};
+use tokio::io::{AsyncRead, AsyncWrite, ReadBuf};
+use tokio_rustls::{
+    TlsAcceptor, TlsConnector, TlsStream,
+    rustls::{
+        self, ClientConfig, ServerConfig, DigitallySignedStruct, DistinguishedName, SignatureScheme, CertificateError,
+        client::danger::{ServerCertVerifier, ServerCertVerified, HandshakeSignatureValid},
+        server::danger::{ClientCertVerifier, ClientCertVerified},
+        crypto::{CryptoProvider, WebPkiSupportedAlgorithms, verify_tls12_signature, verify_tls13_signature},
+        pki_types::{CertificateDer, PrivateKeyDer, ServerName, UnixTime}
+    }
+};
+use std::{
+    fs::File, io::{self, BufReader}, path::Path, pin::Pin, sync::Arc, task::{Context, Poll}
+};
+
+/// A connection to another member, encrypted if the group uses TLS.
+pub(crate) enum PeerStream {
+    Plain(Stream),
+    Tls(Box<TlsStream<Stream>>)
+}
+
+impl AsyncRead for PeerStream {
+    fn poll_read(self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &mut ReadBuf<'_>) -> Poll<io::Result<()>> {
+        match self.get_mut() {
+            PeerStream::Plain(s) => Pin::new(s).poll_read(cx, buf),
+            PeerStream::Tls(s) => Pin::new(s.as_mut()).poll_read(cx, buf)
+        }
+    }
+}
+
+impl AsyncWrite for PeerStream {
+    fn poll_write(self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &[u8]) -> Poll<io::Result<usize>> {
+        match self.get_mut() {
+            PeerStream::Plain(s) => Pin::new(s).poll_write(cx, buf),
+            PeerStream::Tls(s) => Pin::new(s.as_mut()).poll_write(cx, buf)
+        }
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        match self.get_mut() {
+            PeerStream::Plain(s) => Pin::new(s).poll_flush(cx),
+            PeerStream::Tls(s) => Pin::new(s.as_mut()).poll_flush(cx)
+        }
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        match self.get_mut() {
+            PeerStream::Plain(s) => Pin::new(s).poll_shutdown(cx),
+            PeerStream::Tls(s) => Pin::new(s.as_mut()).poll_shutdown(cx)
+        }
+    }
+}
+
+fn invalid_data<E>(error: E) -> io::Error where E: Into<Box<dyn std::error::Error + Send + Sync>> {
+    io::Error::new(io::ErrorKind::InvalidData, error)
+}
+
+pub(crate) fn load_certificate(path: &Path) -> io::Result<CertificateDer<'static>> {
+    let mut rdr = BufReader::new(File::open(path)?);
+    let cert = rustls_pemfile::certs(&mut rdr).next();
+    match cert {
+        Some(cert) => cert,
+        None => Err(invalid_data(format!("no certificate in {}", path.display())))
+    }
+}
+
+fn load_private_key(path: &Path) -> io::Result<PrivateKeyDer<'static>> {
+    let mut rdr = BufReader::new(File::open(path)?);
+    match rustls_pemfile::private_key(&mut rdr)? {
+        Some(key) => Ok(key),
+        None => Err(invalid_data(format!("no private key in {}", path.display())))
+    }
+}
+
+/// The certificates of every member and this node's private key.
+///
+/// Members are authenticated by certificate pinning: a peer is accepted only
+/// if it presents exactly the certificate listed for it in the config, so
+/// self-signed certificates work and no certificate authority is involved.
+/// Host names are not checked.
+pub(crate) struct Tls {
+    provider: Arc<CryptoProvider>,
+    certificates: Vec<CertificateDer<'static>>,
+    key: PrivateKeyDer<'static>,
+    node_id: NodeId,
+    acceptor: TlsAcceptor
+}
+
+impl Tls {
+    /// Load the certificates listed in `config`, or return `None` if the
+    /// group does not use TLS.
+    pub fn from_config(config: &Config, node_id: NodeId) -> io::Result<Option<Self>> {
+        let key_path = match &config.options.tls_private_key {
+            Some(path) => path,
+            None if config.uses_tls() => return Err(invalid_data("the config lists certificates but no private key was given")),
+            None => return Ok(None)
+        };
+
+        let certificates = (0..config.len())
+            .map(|id| match &config.get(id).unwrap().certificate {
+                Some(path) => load_certificate(path),
+                None => Err(invalid_data(format!("no certificate is listed for node {}", id)))
+            })
+            .collect::<io::Result<Vec<_>>>()?;
+        let key = load_private_key(key_path)?;
+
+        let provider = Arc::new(rustls::crypto::ring::default_provider());
+        let verifier = MemberCertificates {
+            certificates: certificates.clone(),
+            algorithms: provider.signature_verification_algorithms
+        };
+        let server_config = ServerConfig::builder_with_provider(provider.clone())
+            .with_safe_default_protocol_versions()
+            .map_err(invalid_data)?
+            .with_client_cert_verifier(Arc::new(verifier))
+            .with_single_cert(vec![certificates[node_id].clone()], key.clone_key())
+            .map_err(invalid_data)?;
+
+        Ok(Some(Self {
+            provider,
+            certificates,
+            key,
+            node_id,
+            acceptor: TlsAcceptor::from(Arc::new(server_config))
+        }))
+    }
+
+    /// Open TLS on a connection to `node_id` at `address`, which must present
+    /// its own certificate.
+    pub async fn connect(&self, stream: Stream, node_id: NodeId, address: &Address) -> io::Result<PeerStream> {
+        let verifier = PinnedCertificate {
+            certificate: self.certificates[node_id].clone(),
+            algorithms: self.provider.signature_verification_algorithms
+        };
+        let client_config = ClientConfig::builder_with_provider(self.provider.clone())
+            .with_safe_default_protocol_versions()
+            .map_err(invalid_data)?
+            .dangerous()
+            .with_custom_certificate_verifier(Arc::new(verifier))
+            .with_client_auth_cert(vec![self.certificates[self.node_id].clone()], self.key.clone_key())
+            .map_err(invalid_data)?;
+
+        let server_name = ServerName::try_from(address.server_name().to_owned()).map_err(invalid_data)?;
+        let stream = TlsConnector::from(Arc::new(client_config)).connect(server_name, stream).await?;
+        Ok(PeerStream::Tls(Box::new(stream.into())))
+    }
+
+    /// Open TLS on a connection from a peer, which must present the
+    /// certificate of one of the members.
+    pub async fn accept(&self, stream: Stream) -> io::Result<PeerStream> {
+        let stream = self.acceptor.accept(stream).await?;
+        Ok(PeerStream::Tls(Box::new(stream.into())))
+    }
+
+    /// Whether the peer on `stream` presented the certificate of `node_id`.
+    pub fn authenticate(&self, stream: &PeerStream, node_id: NodeId) -> bool {
+        let presented = match stream {
+            PeerStream::Tls(s) => s.get_ref().1.peer_certificates().and_then(|certs| certs.first()),
+            PeerStream::Plain(_) => None
+        };
+
+        presented.is_some_and(|cert| *cert == self.certificates[node_id])
+    }
+}
+
+/// Accepts a server only if it presents one particular certificate.
+#[derive(Debug)]
+struct PinnedCertificate {
+    certificate: CertificateDer<'static>,
+    algorithms: WebPkiSupportedAlgorithms
+}
+
+impl ServerCertVerifier for PinnedCertificate {
+    fn verify_server_cert(&self, end_entity: &CertificateDer<'_>, _intermediates: &[CertificateDer<'_>],
+            _server_name: &ServerName<'_>, _ocsp_response: &[u8], _now: UnixTime) -> Result<ServerCertVerified, rustls::Error> {
+        match *end_entity == self.certificate {
+            true => Ok(ServerCertVerified::assertion()),
+            false => Err(rustls::Error::InvalidCertificate(CertificateError::ApplicationVerificationFailure))
+        }
+    }
+
+    fn verify_tls12_signature(&self, message: &[u8], cert: &CertificateDer<'_>, dss: &DigitallySignedStruct)
+            -> Result<HandshakeSignatureValid, rustls::Error> {
+        verify_tls12_signature(message, cert, dss, &self.algorithms)
+    }
+
+    fn verify_tls13_signature(&self, message: &[u8], cert: &CertificateDer<'_>, dss: &DigitallySignedStruct)
+            -> Result<HandshakeSignatureValid, rustls::Error> {
+        verify_tls13_signature(message, cert, dss, &self.algorithms)
+    }
+
+    fn supported_verify_schemes(&self) -> Vec<SignatureScheme> {
+        self.algorithms.supported_schemes()
+    }
+}
+
+/// Accepts a client that presents the certificate of any member. Which member
+/// it is gets checked against its handshake afterwards.
+#[derive(Debug)]
+struct MemberCertificates {
+    certificates: Vec<CertificateDer<'static>>,
+    algorithms: WebPkiSupportedAlgorithms
+}
+
+impl ClientCertVerifier for MemberCertificates {
+    fn root_hint_subjects(&self) -> &[DistinguishedName] {
+        &[]
+    }
+
+    fn verify_client_cert(&self, end_entity: &CertificateDer<'_>, _intermediates: &[CertificateDer<'_>], _now: UnixTime)
+            -> Result<ClientCertVerified, rustls::Error> {
+        match self.certificates.iter().any(|cert| cert == end_entity) {
+            true => Ok(ClientCertVerified::assertion()),
+            false => Err(rustls::Error::InvalidCertificate(CertificateError::ApplicationVerificationFailure))
+        }
+    }
+
+    fn verify_tls12_signature(&self, message: &[u8], cert: &CertificateDer<'_>, dss: &DigitallySignedStruct)
+            -> Result<HandshakeSignatureValid, rustls::Error> {
+        verify_tls12_signature(message, cert, dss, &self.algorithms)
+    }
+
+    fn verify_tls13_signature(&self, message: &[u8], cert: &CertificateDer<'_>, dss: &DigitallySignedStruct)
+            -> Result<HandshakeSignatureValid, rustls::Error> {
+        verify_tls13_signature(message, cert, dss, &self.algorithms)
+    }
+
+    fn supported_verify_schemes(&self) -> Vec<SignatureScheme> {
+        self.algorithms.supported_schemes()
+    }
+}
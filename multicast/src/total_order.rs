@@ -1,40 +1,89 @@
-use super::connection_pool::ConnectionPool;
-use super::reliable::ReliableMulticast;
-use super::{Multicast, MulticastError};
+use super::connection_pool::{ConnectionPool, JoinListener};
+use super::reliable::{ReliableMulticast, ReliableNetworkMessage};
+use super::member::{MemberStateMessage, MulticastMemberHandle};
+use super::{Multicast, MulticastError, ConnectError};
 use super::config::{Config, NodeId};
 use super::pipe::{UnboundedPipe, unbounded_pipe};
-
-use tokio::sync::mpsc::{UnboundedSender, UnboundedReceiver, unbounded_channel};
+use super::wal::DeliveryLog;
+use super::builder::{Options, MulticastBuilder};
+use super::channel::{self, OverflowPolicy};
+use super::tls::PeerStream;
+use super::handshake::Handshake;
+use super::reconnect::Reconnect;
+use super::stats::{StatsRegistry, ConnectionStats};
+use super::protocol::*;
+
+use tokio::sync::{mpsc::{UnboundedSender, UnboundedReceiver, unbounded_channel}, oneshot};
 use tokio::task::JoinHandle;
-use serde::{Serialize, Deserialize, de::DeserializeOwned};
-use std::{collections::{HashSet, HashMap}, cmp::Reverse};
+use serde::{Serialize, de::DeserializeOwned};
+use std::{collections::{HashSet, HashMap, VecDeque}, cmp::Reverse, time::{Duration, SystemTime, UNIX_EPOCH}};
 use log::{trace, error, log_enabled, Level};
 use priority_queue::PriorityQueue;
 use async_trait::async_trait;
-use tokio::{select, time};
-use std::cmp::Ordering;
+use tokio::{select, time::{self, Instant}};
+
+/// How long to wait after a member fails before dropping its unconfirmed
+/// messages. The wait must outlast any copy of the failed member's messages
+/// that is still being forwarded by the remaining members.
+#[derive(Clone, Copy, Debug)]
+pub enum FlushTimeout {
+    /// Always wait this long.
+    Fixed(Duration),
+    /// Wait `multiplier` times the largest round trip time measured to the
+    /// remaining members, kept between `min` and `max`. Waits `max` until a
+    /// round trip time has been measured. A negative or NaN `multiplier`
+    /// waits `min`.
+    Adaptive { multiplier: f64, min: Duration, max: Duration }
+}
+
+impl Default for FlushTimeout {
+    fn default() -> Self {
+        FlushTimeout::Fixed(Duration::from_secs(4))
+    }
+}
 
-static MAX_MESSAGE_LATENCY_SECS: u64 = 4;
+type MemberSender<M> = channel::Sender<MemberStateMessage<ReliableNetworkMessage<TotalOrderNetworkMessage<M>>>>;
+type Delivery<M> = Result<(M, Option<MessagePriority>), MulticastError>;
+/// Told once this node has joined the group, or which members never answered
+/// if it gave up.
+type JoinedSender = oneshot::Sender<Result<(), Vec<NodeId>>>;
 
 struct QueuedMessage<M> {
-    message: M,
+    message: OrderedMessage<M>,
     is_deliverable: bool,
+    /// The members that must propose a priority for this node's own message. 
+    /// Fixed when the message is requested so that members admitted later are
+    /// never waited on. Empty for messages from other nodes.
+    voters: HashSet<NodeId>,
     votes: HashSet<NodeId>
 }
 
 impl<M> QueuedMessage<M> {
-    fn new(message: M) -> Self {
+    fn new(message: OrderedMessage<M>) -> Self {
         Self {
             message,
             is_deliverable: false,
+            voters: Default::default(),
             votes: Default::default()
         }
     }
 
+    fn with_voters(message: OrderedMessage<M>, voters: HashSet<NodeId>) -> Self {
+        Self { voters, ..Self::new(message) }
+    }
+
     fn add_voter(&mut self, voter: NodeId) {
         self.votes.insert(voter);
     }
 
+    /// Whether every required voter that is still a member has voted.
+    fn has_all_votes(&self, members: &HashSet<NodeId>) -> bool {
+        self.voters
+            .iter()
+            .filter(|voter| members.contains(voter))
+            .all(|voter| self.votes.contains(voter))
+    }
+
     fn is_deliverable(&self) -> bool {
         self.is_deliverable
     }
@@ -44,76 +93,38 @@ impl<M> QueuedMessage<M> {
     }
 }
 
-#[derive(Debug, Serialize, Deserialize, Clone)]
-pub enum TotalOrderNetworkMessage<M> {
-    PriorityRequest(PriorityRequestArgs<M>),
-    PriorityProposal(PriorityProposalArgs),
-    PriorityMessage(PriorityMessageArgs),
-    DirectMessage(M)
-}
-
-#[derive(Clone, Debug, Serialize, Deserialize)]
-pub struct PriorityRequestArgs<M> {
-    pub local_id: MessageId,
-    pub message: M
-}
-
-#[derive(Clone, Debug, Serialize, Deserialize)]
-pub struct PriorityProposalArgs {
-    pub requester_local_id: MessageId,
-    pub priority: MessagePriority
-}
-
-#[derive(Clone, Debug, Serialize, Deserialize)]
-pub struct PriorityMessageArgs {
-    pub local_id: MessageId,
-    pub priority: MessagePriority,
-}
-
-#[derive(Clone, Copy, Debug, Serialize, Deserialize, Hash, PartialEq, Eq)]
-pub struct MessageId {
-    pub original_sender: NodeId,
-    pub local_id: usize
-}
-
-#[derive(Clone, Copy, Debug, Serialize, Deserialize)]
-pub struct MessagePriority {
-    pub priority: usize,
-    pub proposer: NodeId
-}
-
-impl Ord for MessagePriority {
-    fn cmp(&self, other: &Self) -> Ordering {
-        match self.priority.cmp(&other.priority) {
-            Ordering::Equal => self.proposer.cmp(&other.proposer),
-            x => x
-        }
-    }
-}
-
-impl PartialOrd for MessagePriority {
-    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
-        match self.priority.cmp(&other.priority) {
-            Ordering::Equal => Some(self.proposer.cmp(&other.proposer)),
-            x => Some(x)
-        }
-    }
+/// A message from the application that waits for room in the outgoing queues
+/// of its recipients.
+enum Blocked<M> {
+    Broadcast(M),
+    Direct(M, NodeId)
 }
 
-impl PartialEq for MessagePriority {
-    fn eq(&self, other: &Self) -> bool {
-        self.priority == other.priority && self.proposer == other.proposer
-    }
+/// Tracks this node's own admission into a group that was already running.
+struct JoinProgress {
+    /// The id of the `Join` message this node requested an order for.
+    request: MessageId,
+    /// The agreed priority of the `Join` message, once known.
+    admitted_at: Option<MessagePriority>,
+    /// The members that have not yet sent their pending messages.
+    awaiting_snapshots: HashSet<NodeId>,
+    /// When to give up on joining.
+    deadline: Instant,
+    joined_snd: JoinedSender
 }
 
-impl Eq for MessagePriority {}
-
+/// A multicast implementation that delivers every message to every member in
+/// the same order.
+///
+/// Messages must be `Clone`: a broadcast message stays queued on this node
+/// until its order is agreed, while a copy of it is sent to the group.
 pub struct TotalOrderedMulticast<M> {
     /// Receiver half of the channel to communicate between the handler task and
     /// the deliver API
-    deliver_rcv: UnboundedReceiver<M>,
+    deliver_rcv: channel::Receiver<Delivery<M>>,
     broadcast_queue: UnboundedPipe<Result<(), MulticastError>, M>,
     send_queue: UnboundedPipe<Result<(), MulticastError>, (M, NodeId)>,
+    stats: StatsRegistry,
     work_thread_handle: JoinHandle<()>
 }
 
@@ -130,11 +141,46 @@ struct TotalOrderedMulticastWorkData<M> {
     queued_messages: HashMap<MessageId, QueuedMessage<M>>,
     
     next_local_id: usize,
+    /// When this node started, which numbers its messages apart from those
+    /// of any earlier run of the same node.
+    incarnation: u64,
     next_priority_proposal: usize,
 
+    /// The members whose votes are required to order a new message. Nodes 
+    /// that join are added once their `Join` message is delivered.
+    members: HashSet<NodeId>,
+
     /// A reliable multicast client that delivers messages from members to this node
     reliable_multicast: ReliableMulticast<TotalOrderNetworkMessage<M>>,
 
+    /// Accepts connections from nodes joining the running group.
+    joins: JoinListener,
+
+    /// Used to spawn handlers for members that join the running group.
+    to_engine: MemberSender<M>,
+    options: Options,
+
+    /// Nodes that failed and whose unconfirmed messages are not yet flushed.
+    departed: HashSet<NodeId>,
+
+    /// Connections from departed nodes that are rejoining, admitted once the
+    /// node's earlier messages have been flushed.
+    deferred_joins: HashMap<NodeId, (PeerStream, Handshake)>,
+
+    /// `Some(_)` while this node is joining a running group.
+    joining: Option<JoinProgress>,
+
+    /// Messages ordered at or before this priority were agreed before this 
+    /// node joined the group and are not delivered.
+    delivery_floor: Option<MessagePriority>,
+
+    /// If `Some(_)`, every message is appended to this log before delivery.
+    delivery_log: Option<DeliveryLog>,
+
+    /// Set once appending to the delivery log failed, after which no more
+    /// messages are delivered.
+    log_failed: bool,
+
     /// Receives message to flush the priority queue of all messages from a dead 
     /// sender after waiting for a particular timeout.
     pq_flush_rcv: UnboundedReceiver<NodeId>,
@@ -143,9 +189,15 @@ struct TotalOrderedMulticastWorkData<M> {
     /// from a dead sender after waiting for a particular timeout.
     pq_flush_snd: UnboundedSender<NodeId>,
 
-    /// Sender half of the channel to communicate between the handler task and
-    /// the deliver API
-    deliver_snd: UnboundedSender<M>,
+    /// Messages ready for delivery, each paired with its position in the total
+    /// order or `None` if it was sent directly to this node. Moved to the
+    /// deliver API as long as its channel has room.
+    /// No more messages are read from members while this is full.
+    pending_deliveries: VecDeque<Delivery<M>>,
+
+    /// The broadcast or direct message the application is waiting on, if its
+    /// recipients' queues are full and the overflow policy is to block.
+    blocked: Option<Blocked<M>>,
 
     /// One half of a pipe that receives messages to broadcast and yields the 
     /// result of the broadcast attempt
@@ -163,6 +215,7 @@ impl<M> TotalOrderedMulticastWorkData<M> {
         
         MessageId {
             original_sender: self.node_id,
+            incarnation: self.incarnation,
             local_id
         }
     }
@@ -178,7 +231,7 @@ impl<M> TotalOrderedMulticastWorkData<M> {
     }
 
     fn sync_next_priority(&mut self, other_priority: &MessagePriority) {
-        if other_priority.priority > self.next_priority_proposal {
+        if other_priority.priority >= self.next_priority_proposal {
             self.next_priority_proposal = other_priority.priority + 1;
         }
     }
@@ -210,7 +263,7 @@ impl<M> TotalOrderedMulticastWorkData<M> {
             .filter(|(mid, qm)| {
                 mid.original_sender == member_id && !qm.is_deliverable()
             })
-            .map(|(mid, _)| mid.clone())
+            .map(|(mid, _)| *mid)
             .collect::<Vec<_>>();
 
         for mid in to_remove.iter() {
@@ -222,13 +275,14 @@ impl<M> TotalOrderedMulticastWorkData<M> {
 
     async fn recheck_pq_delivery_status(&mut self) -> Result<(), MulticastError> where M: Serialize + Send {
         let to_confirm = self.queued_messages.iter_mut()
-            .filter(|(_, qm)| 
-                qm.votes.is_superset(self.reliable_multicast.members())
+            .filter(|(mid, qm)| 
+                mid.original_sender == self.node_id
+                    && qm.has_all_votes(&self.members)
                     && !qm.is_deliverable()
             )
             .map(|(mid, qm)| {
                 qm.mark_deliverable();
-                mid.clone()
+                *mid
             })
             .collect::<Vec<_>>();
 
@@ -239,37 +293,107 @@ impl<M> TotalOrderedMulticastWorkData<M> {
         Ok(())
     }
 
-    fn try_empty_pq(&mut self) -> Result<(), MulticastError> {
-        while let Some((id, _)) = self.pq.peek() {
+    async fn try_empty_pq(&mut self) -> Result<(), MulticastError> where M: Serialize + Send + Clone {
+        // Nothing is delivered until this node knows where it joined the order
+        if self.joining.is_some() || self.log_failed {
+            return Ok(());
+        }
+
+        while let Some((id, priority)) = self.pq.peek() {
             if log_enabled!(Level::Trace) { self.print_pq(); }
 
             let qm = self.queued_messages.get(id).unwrap();
-            if qm.is_deliverable() {
-                let qm = self.queued_messages.remove(id).unwrap();
-                self.pq.pop();
-                self.deliver_snd
-                    .send(qm.message)
-                    .map_err(|_| MulticastError::InternalError)?;
-            } else {
+            if !qm.is_deliverable() {
                 break;
             }
+
+            let (id, priority) = (*id, priority.0);
+            let qm = self.queued_messages.remove(&id).unwrap();
+            self.pq.pop();
+
+            if self.delivery_floor.is_some_and(|floor| priority <= floor) {
+                trace!("Skipping {:?}, which was ordered before this node joined", id);
+                continue;
+            }
+
+            match qm.message {
+                OrderedMessage::Application(msg) => {
+                    if let Some(log) = self.delivery_log.as_mut() {
+                        if let Err(e) = log.append(&msg, priority).await {
+                            // Delivering without logging would quietly give up durability
+                            error!("Failed to log delivery of {:?}, so no more messages are delivered: {}", id, e);
+                            self.delivery_log = None;
+                            self.log_failed = true;
+                            self.pending_deliveries.push_back(Err(MulticastError::LogFailed(e)));
+                            break;
+                        }
+                    }
+
+                    self.pending_deliveries.push_back(Ok((msg, Some(priority))))
+                },
+                OrderedMessage::Join(node_id) => self.admit_joined_member(node_id).await
+            }
         }
         if log_enabled!(Level::Trace) { self.print_pq(); }
 
         Ok(())
     }
 
-    /// Request a priority for this message from all other nodes in the group.
-    async fn request_priority(&mut self, msg: M) -> Result<(), MulticastError> where M: Serialize + Send {
+    /// Place one of this node's own messages in the priority queue, waiting on 
+    /// votes from every current member.
+    fn queue_own_message(&mut self, message: OrderedMessage<M>) -> MessageId {
         let local_id = self.get_local_id();
         let my_pri = self.get_next_priority();
 
         self.pq.push(local_id, Reverse(my_pri));
-        let queued = QueuedMessage::new(unsafe { std::ptr::read(&msg) });
+        let queued = QueuedMessage::with_voters(message, self.members.clone());
         self.queued_messages.insert(local_id, queued);
+
+        local_id
+    }
+
+    /// Request a priority for this message from all other nodes in the group.
+    async fn request_priority(&mut self, msg: M) -> Result<(), MulticastError> where M: Serialize + Send + Clone {
+        let message = OrderedMessage::Application(msg);
+        let local_id = self.queue_own_message(message.clone());
         
-        let rq_type = PriorityRequestArgs { local_id, message: msg };
-        self.reliable_multicast.broadcast(TotalOrderNetworkMessage::PriorityRequest(rq_type)).await
+        let rq_type = PriorityRequestArgs { local_id, message };
+        match self.reliable_multicast.broadcast_unchecked(TotalOrderNetworkMessage::PriorityRequest(rq_type)) {
+            Err(e @ (MulticastError::EncodeFailed(_) | MulticastError::MessageTooLarge { .. })) => {
+                // No member saw the request, so it must not hold up the total order
+                self.pq.remove(&local_id);
+                self.queued_messages.remove(&local_id);
+                Err(e)
+            },
+            Err(MulticastError::BroadcastError(failed)) => {
+                // The message would wait forever on proposals from members that
+                // never got the request, so they are removed as if they failed.
+                // Every other member got it, so it is still delivered.
+                error!("Removing nodes {:?} from the group: could not send them a priority request", failed);
+                self.handle_failure(MulticastError::BroadcastError(failed)).await;
+                Ok(())
+            },
+            result => result
+        }
+    }
+
+    /// Ask the members of a running group to agree on where this node joins
+    /// the total order.
+    async fn request_join(&mut self, joined_snd: JoinedSender) where M: Serialize + Send + Clone {
+        let message = OrderedMessage::Join(self.node_id);
+        let local_id = self.queue_own_message(message.clone());
+        self.joining = Some(JoinProgress {
+            request: local_id,
+            admitted_at: None,
+            awaiting_snapshots: self.members.clone(),
+            deadline: Instant::now() + self.options.connect_timeout,
+            joined_snd
+        });
+
+        let rq_type = PriorityRequestArgs { local_id, message };
+        if let Err(e) = self.reliable_multicast.broadcast_unchecked(TotalOrderNetworkMessage::PriorityRequest(rq_type)) {
+            self.handle_failure(e).await
+        }
     }
 
     /// We got a request from another process for priority, so propose a priority.
@@ -279,11 +403,19 @@ impl<M> TotalOrderedMulticastWorkData<M> {
         let priority = self.get_next_priority();
         let recipient = requester_local_id.original_sender;
 
-        self.pq.push(requester_local_id, Reverse(priority));
-        self.queued_messages.insert(
-            requester_local_id,
-            QueuedMessage::new(request.message)
-        );
+        // A joining node may already know of this message from a snapshot
+        match self.queued_messages.get(&requester_local_id) {
+            Some(qm) => if !qm.is_deliverable() {
+                self.pq.push_decrease(requester_local_id, Reverse(priority));
+            },
+            None => {
+                self.pq.push(requester_local_id, Reverse(priority));
+                self.queued_messages.insert(
+                    requester_local_id,
+                    QueuedMessage::new(request.message)
+                );
+            }
+        }
 
         let proposed_pri = PriorityProposalArgs {
             requester_local_id,
@@ -291,25 +423,37 @@ impl<M> TotalOrderedMulticastWorkData<M> {
         };
         
         let msg_type = TotalOrderNetworkMessage::PriorityProposal(proposed_pri);
-        self.reliable_multicast.send_to(msg_type, recipient).await
+        self.reliable_multicast.send_unchecked(msg_type, recipient)
     }
 
-    async fn process_priority_proposal(&mut self, proposal: PriorityProposalArgs) -> Result<(), MulticastError> where M: Serialize + Send {
+    async fn process_priority_proposal(&mut self, proposal: PriorityProposalArgs) -> Result<(), MulticastError> where M: Serialize + Send + Clone {
         trace!("Received priority proposal for {:?} from Node {}", proposal.requester_local_id, proposal.priority.proposer);
         let mid = proposal.requester_local_id;
-        let qm = self.queued_messages.get_mut(&mid).unwrap();
+        let qm = match self.queued_messages.get_mut(&mid) {
+            Some(qm) => qm,
+            None => {
+                error!("Attempt to retrieve message with id = {:?} from queued_messages failed", mid);
+                return Ok(());
+            }
+        };
+
+        // Proposals from nodes that joined after the request are not counted
+        if qm.is_deliverable() || !qm.voters.contains(&proposal.priority.proposer) {
+            trace!("Ignoring priority proposal for {:?} from Node {}", mid, proposal.priority.proposer);
+            return Ok(());
+        }
 
         // We are reversing the priority, so push decrease will be inverted 
         // and push if the new inner priority is greater than the old priority
         self.pq.push_decrease(mid, Reverse(proposal.priority));
         qm.add_voter(proposal.priority.proposer);
 
-        if qm.votes.is_superset(self.reliable_multicast.members()) {
+        if qm.has_all_votes(&self.members) {
             trace!("Received enough votes for delivery for {:?}", proposal.requester_local_id);
             qm.mark_deliverable();
             
             self.confirmed_message_priority(mid).await?;
-            self.try_empty_pq()?;
+            self.try_empty_pq().await?;
         }
 
         Ok(())
@@ -321,27 +465,233 @@ impl<M> TotalOrderedMulticastWorkData<M> {
         let priority = self.pq
             .get_priority(&message_id)
             .unwrap().0;
+        self.sync_next_priority(&priority);
+
+        if let Some(progress) = self.joining.as_mut() {
+            if progress.request == message_id {
+                trace!("This node joins the group at {:?}", priority);
+                progress.admitted_at = Some(priority);
+                self.check_join_complete();
+            }
+        }
         
-        self.reliable_multicast.broadcast(
+        self.reliable_multicast.broadcast_unchecked(
             TotalOrderNetworkMessage::PriorityMessage(PriorityMessageArgs {
                 local_id: message_id,
                 priority
-            })).await
+            }))
+    }
+
+    /// A node was admitted to the group at this point in the total order, so
+    /// require its votes from now on and send it every message this node has 
+    /// yet to deliver.
+    async fn admit_joined_member(&mut self, node_id: NodeId) where M: Serialize + Send + Clone {
+        if node_id == self.node_id {
+            return;
+        } else if !self.reliable_multicast.members().contains(&node_id) {
+            error!("Node {} joined the group but is not connected to this node", node_id);
+            return;
+        }
+
+        trace!("Admitting node {} to the group", node_id);
+        self.members.insert(node_id);
+
+        let pending = self.pq
+            .iter()
+            .map(|(mid, pri)| {
+                let qm = self.queued_messages.get(mid).unwrap();
+                PendingMessage {
+                    local_id: *mid,
+                    message: qm.message.clone(),
+                    priority: pri.0,
+                    is_deliverable: qm.is_deliverable()
+                }
+            })
+            .collect();
+
+        let snapshot = JoinSnapshotArgs { sender: self.node_id, pending };
+        if let Err(e) = self.reliable_multicast.send_unchecked(TotalOrderNetworkMessage::JoinSnapshot(snapshot), node_id) {
+            error!("Failed to send pending messages to node {}: {:?}", node_id, e);
+        }
     }
 
-    fn remove_node(&mut self, node_id: NodeId) {
+    /// Merge the pending messages of a member into the priority queue while
+    /// this node is joining the group.
+    fn apply_join_snapshot(&mut self, snapshot: JoinSnapshotArgs<M>) {
+        if self.joining.is_none() {
+            error!("Received pending messages from node {} after joining", snapshot.sender);
+            return;
+        }
+
+        trace!("Received {} pending messages from node {}", snapshot.pending.len(), snapshot.sender);
+        for entry in snapshot.pending {
+            self.sync_next_priority(&entry.priority);
+            let mid = entry.local_id;
+
+            match self.queued_messages.get_mut(&mid) {
+                Some(qm) => if !qm.is_deliverable() {
+                    if entry.is_deliverable {
+                        qm.mark_deliverable();
+                        self.pq.change_priority(&mid, Reverse(entry.priority));
+                    } else {
+                        self.pq.push_decrease(mid, Reverse(entry.priority));
+                    }
+                },
+                None => {
+                    // The outcome of an unconfirmed message can only be learned
+                    // from a sender this node is connected to
+                    let sender = mid.original_sender;
+                    if !entry.is_deliverable && !self.reliable_multicast.members().contains(&sender) {
+                        continue;
+                    }
+
+                    let mut qm = QueuedMessage::new(entry.message);
+                    if entry.is_deliverable { qm.mark_deliverable(); }
+                    self.queued_messages.insert(mid, qm);
+                    self.pq.push(mid, Reverse(entry.priority));
+                }
+            }
+        }
+
+        if let Some(progress) = self.joining.as_mut() {
+            progress.awaiting_snapshots.remove(&snapshot.sender);
+        }
+        self.check_join_complete();
+    }
+
+    /// Finish joining once the admission point is agreed and every member has
+    /// sent the messages it has yet to deliver.
+    fn check_join_complete(&mut self) {
+        let complete = self.joining
+            .as_ref()
+            .is_some_and(|p| p.admitted_at.is_some() && p.awaiting_snapshots.is_empty());
+
+        if complete {
+            let progress = self.joining.take().unwrap();
+            trace!("Finished joining the group at {:?}", progress.admitted_at);
+            self.delivery_floor = progress.admitted_at;
+            let _ = progress.joined_snd.send(Ok(()));
+        }
+    }
+
+    /// Give up on joining, naming the members that have not yet agreed on
+    /// where this node joins or sent the messages it has yet to deliver.
+    fn abandon_join(&mut self) {
+        if let Some(progress) = self.joining.take() {
+            let mut missing: Vec<NodeId> = match progress.admitted_at {
+                Some(_) => progress.awaiting_snapshots.into_iter().collect(),
+                None => self.queued_messages
+                    .get(&progress.request)
+                    .map(|qm| qm.voters.iter().filter(|voter| !qm.votes.contains(voter)).cloned().collect())
+                    .unwrap_or_default()
+            };
+            missing.sort_unstable();
+            error!("Giving up on joining the group: nodes {:?} did not answer", missing);
+            let _ = progress.joined_snd.send(Err(missing));
+        }
+    }
+
+    /// Admit a connection from a node that is joining the running group.
+    fn accept_join(&mut self, stream: PeerStream, theirs: Handshake) where M: 'static + Send + Serialize + DeserializeOwned {
+        let node_id = theirs.node_id;
+        if self.reliable_multicast.members().contains(&node_id) {
+            error!("Rejecting join from node {}: it is already a member", node_id);
+        } else if self.departed.contains(&node_id) {
+            trace!("Deferring join from node {} until its earlier messages are flushed", node_id);
+            self.deferred_joins.insert(node_id, (stream, theirs));
+        } else {
+            trace!("Node {} connected to join the group", node_id);
+            let reconnect = Reconnect::wait_for(&theirs, &self.options, self.joins.reconnections());
+            let handle = MulticastMemberHandle::spawn(stream, &theirs, self.to_engine.clone(), &self.options, 
+                self.reliable_multicast.flow().drained.clone(), self.reliable_multicast.stats().register(node_id), reconnect);
+            self.reliable_multicast.add_member(handle);
+        }
+    }
+
+    /// Whether there is room to deliver more messages from members. Members
+    /// are always read while the application is blocked on a broadcast, since
+    /// it cannot call `deliver` until the other members make room for it.
+    fn can_receive(&self) -> bool {
+        self.blocked.is_some()
+            || self.options.channel_capacity.is_none_or(|capacity| self.pending_deliveries.len() < capacity)
+    }
+
+    /// Send a message from the application once its recipients have room for
+    /// it, and answer the application with the result.
+    async fn handle_outgoing(&mut self, outgoing: Blocked<M>) where M: Send + Serialize + Clone {
+        let recipient = match &outgoing {
+            Blocked::Broadcast(_) => None,
+            Blocked::Direct(_, recipient) => Some(*recipient)
+        };
+
+        let full = self.reliable_multicast.full_members(recipient);
+        let resp = if full.is_empty() {
+            match outgoing {
+                Blocked::Broadcast(msg) => self.request_priority(msg).await,
+                Blocked::Direct(msg, recipient) => self.reliable_multicast
+                    .send_unchecked(TotalOrderNetworkMessage::DirectMessage(msg), recipient)
+            }
+        } else {
+            match self.options.overflow_policy {
+                OverflowPolicy::Block => {
+                    self.blocked = Some(outgoing);
+                    return;
+                },
+                OverflowPolicy::Drop => Err(MulticastError::Dropped(full)),
+                OverflowPolicy::Error => Err(MulticastError::Backpressure(full))
+            }
+        };
+
+        match recipient {
+            None => self.broadcast_queue.send(resp).unwrap(),
+            Some(_) => self.send_queue.send(resp).unwrap()
+        }
+    }
+
+    /// How long to wait for messages from a failed member to trickle in.
+    fn flush_delay(&self) -> Duration {
+        match self.options.flush_timeout {
+            FlushTimeout::Fixed(delay) => delay,
+            FlushTimeout::Adaptive { multiplier, min, max } => self.reliable_multicast
+                .max_round_trip()
+                // A negative or NaN multiplier waits `min`, an overflowing one `max`
+                .map_or(max, |rtt| Duration::try_from_secs_f64(rtt.as_secs_f64() * multiplier)
+                    .unwrap_or(if multiplier > 0.0 { max } else { min }))
+                .max(min)
+                .min(max)
+        }
+    }
+
+    fn remove_node(&mut self, node_id: NodeId) where M: Serialize {
         self.reliable_multicast.remove_member(&node_id);
+        self.members.remove(&node_id);
+        self.departed.insert(node_id);
+
+        if let Some(progress) = self.joining.as_mut() {
+            progress.awaiting_snapshots.remove(&node_id);
+        }
+        self.check_join_complete();
 
         let pq_flush_snd_clone = self.pq_flush_snd.clone();
+        let delay = self.flush_delay();
         tokio::spawn(async move {
-            trace!("Waiting for {}s before flushing PQ of all messages from node {}...", MAX_MESSAGE_LATENCY_SECS, node_id);
-            time::sleep(time::Duration::from_secs(MAX_MESSAGE_LATENCY_SECS)).await;
+            trace!("Waiting for {:?} before flushing PQ of all messages from node {}...", delay, node_id);
+            time::sleep(delay).await;
             trace!("Waiting for messages from node {} to trickle in finished...", node_id);
             pq_flush_snd_clone.send(node_id).unwrap();
         });
     }
 
-    async fn handle_failure(&mut self, failure: MulticastError) where M: Send + Serialize {
+    async fn recheck_after_failure(&mut self) where M: Send + Serialize + Clone {
+        if let Err(e) = self.recheck_pq_delivery_status().await {
+            error!("Failed to recheck PQ delivery status: {:?}", e);
+        }
+        if let Err(e) = self.try_empty_pq().await {
+            error!("Failed to empty PQ: {:?}", e);
+        }
+    }
+
+    async fn handle_failure(&mut self, failure: MulticastError) where M: Send + Serialize + Clone {
         use MulticastError::*;
         match failure {
             BroadcastError(failures) => {
@@ -349,36 +699,70 @@ impl<M> TotalOrderedMulticastWorkData<M> {
                     .into_iter()
                     .for_each(|node_id| self.remove_node(node_id));
 
-                self.recheck_pq_delivery_status().await;
-                self.try_empty_pq();
+                self.recheck_after_failure().await;
             },
             ClientDisconnected(node_id) => {
                 self.remove_node(node_id);
-                self.recheck_pq_delivery_status().await;
-                self.try_empty_pq();
+                self.recheck_after_failure().await;
+            },
+            // A hung member would block delivery until its votes arrive, so it
+            // is removed as if it had crashed. It can come back with `join`.
+            ClientSuspected(node_id) => {
+                error!("Removing node {} from the group: suspected to have failed", node_id);
+                self.remove_node(node_id);
+                self.recheck_after_failure().await;
+            },
+            // Without all of its messages, the rest of the member's messages
+            // could wait on proposals that never come
+            MessagesLost { origin, from, to } => {
+                error!("Removing node {} from the group: its messages {} to {} were lost", origin, from, to - 1);
+                self.remove_node(origin);
+                self.recheck_after_failure().await;
             },
-            AllClientsDisconnected => todo!(), // TODO figure out what to do when all clients disconnect
-            InvalidRecipient(_) => unreachable!(),
-            InternalError => todo!()
+            ClientRecovered(node_id) => trace!("Node {} recovered after it was removed from the group", node_id),
+            // A member removed while frames for it were still being handled
+            InvalidRecipient(node_id) => trace!("Dropped a protocol message for node {}, which left the group", node_id),
+            AllClientsDisconnected => error!("Every member has disconnected"),
+            EncodeFailed(e) => error!("Could not encode a protocol message: {}", e),
+            MessageTooLarge { size, max } => error!("A protocol message is {} bytes, more than the maximum of {}", size, max),
+            // Protocol messages are queued regardless of how full the queues are
+            Backpressure(members) | Dropped(members) => error!("Could not queue a protocol message for nodes {:?}", members),
+            LogFailed(e) => {
+                self.log_failed = true;
+                self.pending_deliveries.push_back(Err(LogFailed(e)));
+            },
+            InternalError => error!("Internal error in the reliable multicast")
         }
     }
 }
 
-async fn to_protocol_loop<M>(mut data: TotalOrderedMulticastWorkData<M>) where M: Serialize + Send { 
+async fn to_protocol_loop<M>(mut data: TotalOrderedMulticastWorkData<M>, deliver_snd: channel::Sender<Delivery<M>>, 
+        joined_snd: Option<JoinedSender>) 
+        where M: 'static + Serialize + Send + Clone + DeserializeOwned { 
+    if let Some(joined_snd) = joined_snd {
+        data.request_join(joined_snd).await;
+    }
+
+    let drained = data.reliable_multicast.flow().drained.clone();
     loop {
+        let can_receive = data.can_receive();
         select! {
-            Some(broadcast_req) = data.broadcast_queue.recv() => {
-                let resp = data.request_priority(broadcast_req).await;
-                data.broadcast_queue.send(resp).unwrap();
+            _ = time::sleep_until(data.joining.as_ref().map_or_else(Instant::now, |progress| progress.deadline)), 
+                    if data.joining.is_some() => data.abandon_join(),
+            Some(broadcast_req) = data.broadcast_queue.recv(), if data.blocked.is_none() => {
+                data.handle_outgoing(Blocked::Broadcast(broadcast_req)).await
             },
-            Some((msg, recipient)) = data.send_queue.recv() => {
-                let resp = data
-                    .reliable_multicast
-                    .send_to(TotalOrderNetworkMessage::DirectMessage(msg), recipient)
-                    .await;
-                data.send_queue.send(resp).unwrap();
+            Some((msg, recipient)) = data.send_queue.recv(), if data.blocked.is_none() => {
+                data.handle_outgoing(Blocked::Direct(msg, recipient)).await
             },
-            delivery = data.reliable_multicast.deliver() => match delivery {
+            _ = drained.notified(), if data.blocked.is_some() => {
+                let outgoing = data.blocked.take().unwrap();
+                data.handle_outgoing(outgoing).await
+            },
+            Ok(permit) = deliver_snd.reserve(), if !data.pending_deliveries.is_empty() => {
+                permit.send(data.pending_deliveries.pop_front().unwrap())
+            },
+            delivery = data.reliable_multicast.deliver(), if can_receive => match delivery {
                 Ok(msg) => match msg {
                     TotalOrderNetworkMessage::PriorityRequest(request) => {
                         if let Err(e) = data.propose_priority(request).await {
@@ -397,75 +781,205 @@ async fn to_protocol_loop<M>(mut data: TotalOrderedMulticastWorkData<M>) where M
                         match data.queued_messages.get_mut(&mid) {
                             Some(qm) => {
                                 qm.mark_deliverable();
-                                data.pq.push_decrease(mid, Reverse(m.priority));
-                                data.try_empty_pq();
+                                // The agreed priority can be below this node's own
+                                // proposal, if it joined after the request was sent
+                                data.pq.push(mid, Reverse(m.priority));
+                                if let Err(e) = data.try_empty_pq().await {
+                                    error!("Failed to empty PQ: {:?}", e)
+                                }
                             },
                             None => error!("Attempt to retrieve message with id = {:?} from queued_messages failed", mid)
                         }
                     },
-                    TotalOrderNetworkMessage::DirectMessage(m) => { data.deliver_snd.send(m); }
+                    TotalOrderNetworkMessage::DirectMessage(m) => data.pending_deliveries.push_back(Ok((m, None))),
+                    TotalOrderNetworkMessage::JoinSnapshot(snapshot) => {
+                        data.apply_join_snapshot(snapshot);
+                        if let Err(e) = data.try_empty_pq().await {
+                            error!("Failed to empty PQ: {:?}", e)
+                        }
+                    }
                 },
                 Err(failure) => data.handle_failure(failure).await
             },
+            Some((stream, theirs)) = data.joins.recv() => data.accept_join(stream, theirs),
             Some(member_id) = data.pq_flush_rcv.recv() => {
                 data.flush_pq_unconfirmed_messages(member_id);
-                data.try_empty_pq();
+                data.departed.remove(&member_id);
+                if let Some((stream, theirs)) = data.deferred_joins.remove(&member_id) {
+                    data.accept_join(stream, theirs);
+                }
+
+                if let Err(e) = data.try_empty_pq().await {
+                    error!("Failed to empty PQ: {:?}", e)
+                }
             }
         }
     }
 }
 
-#[async_trait]
-impl<M> Multicast<M> for TotalOrderedMulticast<M> where M: Send + Serialize {
-    async fn connect(node_id: usize, config: Config, timeout_secs: u64) -> Self where M: 'static + Serialize + Send + DeserializeOwned { 
+impl<M> TotalOrderedMulticast<M> {
+    fn start(node_id: NodeId, mut pool: ConnectionPool<ReliableNetworkMessage<TotalOrderNetworkMessage<M>>>, config: &Config, 
+            delivery_log: Option<DeliveryLog>, joined_snd: Option<JoinedSender>) -> Self 
+            where M: 'static + Serialize + Send + Clone + DeserializeOwned {
         let (pq_flush_snd, pq_flush_rcv) = unbounded_channel();
-        let (deliver_snd, deliver_rcv) = unbounded_channel();
+        let (deliver_snd, deliver_rcv) = channel::channel(config.options.channel_capacity);
         let (broadcast_queue_snd, broadcast_queue_rcv) = unbounded_pipe();
         let (send_queue_snd, send_queue_rcv) = unbounded_pipe();
 
-        let pool = ConnectionPool::new(node_id)
-            .with_timeout(timeout_secs)
-            .connect(&config)
-            .await;
+        let joins = pool.listen_for_joins(config);
+        let members = pool.group.keys().cloned().collect();
+        let stats = pool.stats.clone();
 
-        trace!("finished connecting to group!");
-
-        let data: TotalOrderedMulticastWorkData<M> = TotalOrderedMulticastWorkData {
+        let mut data: TotalOrderedMulticastWorkData<M> = TotalOrderedMulticastWorkData {
             node_id,
-            reliable_multicast: ReliableMulticast::new(pool.group, pool.from_members),
+            members,
+            reliable_multicast: ReliableMulticast::new(node_id, pool.group, pool.from_members, pool.flow, pool.stats, &config.options, 
+                None),
+            joins,
+            to_engine: pool.client_snd_handle,
+            options: config.options.clone(),
+            departed: HashSet::new(),
+            deferred_joins: HashMap::new(),
+            joining: None,
+            delivery_floor: None,
+            delivery_log,
+            log_failed: false,
             pq: PriorityQueue::new(),
             next_local_id: 0,
+            incarnation: SystemTime::now().duration_since(UNIX_EPOCH).map_or(0, |since| since.as_nanos() as u64),
             next_priority_proposal: 0,
             queued_messages: HashMap::new(),
             pq_flush_rcv,
             pq_flush_snd,
-            deliver_snd,
+            pending_deliveries: VecDeque::new(),
+            blocked: None,
             broadcast_queue: broadcast_queue_rcv,
             send_queue: send_queue_rcv
         };
 
+        // A joining node only delivers what members send after it joined
+        if joined_snd.is_some() {
+            data.reliable_multicast.start_mid_stream();
+        }
+
+        // Never propose a priority at or below one that was already delivered
+        if let Some(position) = data.delivery_log.as_ref().and_then(DeliveryLog::last_position) {
+            data.sync_next_priority(&position);
+        }
+
         TotalOrderedMulticast {
             deliver_rcv,
             broadcast_queue: broadcast_queue_snd,
             send_queue: send_queue_snd,
-            work_thread_handle: tokio::spawn(to_protocol_loop(data))
+            stats,
+            work_thread_handle: tokio::spawn(to_protocol_loop(data, deliver_snd, joined_snd))
+        }
+    }
+
+    /// Join a group that is already running, e.g. after this node crashed and
+    /// restarted. The members agree on a point in the total order at which
+    /// this node is admitted, and only messages ordered after that point are
+    /// delivered to it.
+    pub async fn join(node_id: NodeId, config: Config, timeout_secs: u64) -> Result<Self, ConnectError> 
+            where M: 'static + Serialize + Send + Clone + DeserializeOwned {
+        MulticastBuilder::new(node_id, config)
+            .connect_timeout(Duration::from_secs(timeout_secs))
+            .join_total_order()
+            .await
+    }
+
+    /// Like `join`, but every delivered message is first appended to `log`.
+    pub async fn join_with_log(node_id: NodeId, config: Config, timeout_secs: u64, log: DeliveryLog) -> Result<Self, ConnectError> 
+            where M: 'static + Serialize + Send + Clone + DeserializeOwned {
+        MulticastBuilder::new(node_id, config)
+            .connect_timeout(Duration::from_secs(timeout_secs))
+            .delivery_log(log)
+            .join_total_order()
+            .await
+    }
+
+    /// Like `connect`, but every delivered message is first appended to `log`.
+    /// Messages already in the log can be read with `DeliveryLog::replay` to
+    /// rebuild application state after a restart.
+    pub async fn connect_with_log(node_id: NodeId, config: Config, timeout_secs: u64, log: DeliveryLog) -> Result<Self, ConnectError> 
+            where M: 'static + Serialize + Send + Clone + DeserializeOwned {
+        MulticastBuilder::new(node_id, config)
+            .connect_timeout(Duration::from_secs(timeout_secs))
+            .delivery_log(log)
+            .total_order()
+            .await
+    }
+
+    pub(crate) async fn priv_connect(node_id: NodeId, config: Config, log: Option<DeliveryLog>) -> Result<Self, ConnectError> 
+            where M: 'static + Serialize + Send + Clone + DeserializeOwned {
+        let pool = ConnectionPool::new(node_id, &config.options)
+            .connect(&config)
+            .await?;
+
+        trace!("finished connecting to group!");
+
+        Ok(Self::start(node_id, pool, &config, log, None))
+    }
+
+    pub(crate) async fn priv_join(node_id: NodeId, config: Config, log: Option<DeliveryLog>) -> Result<Self, ConnectError> 
+            where M: 'static + Serialize + Send + Clone + DeserializeOwned {
+        let pool = ConnectionPool::new(node_id, &config.options)
+            .join(&config)
+            .await?;
+
+        trace!("finished connecting to running group, waiting for admission...");
+
+        let time_limit = config.options.connect_timeout;
+        let (joined_snd, joined_rcv) = oneshot::channel();
+        let multicast = Self::start(node_id, pool, &config, log, Some(joined_snd));
+        match joined_rcv.await {
+            Ok(Ok(())) => Ok(multicast),
+            Ok(Err(missing)) => Err(ConnectError::TimedOut { secs: time_limit.as_secs(), missing }),
+            Err(_) => Err(ConnectError::TimedOut { 
+                secs: time_limit.as_secs(), 
+                missing: (0..config.len()).filter(|id| *id != node_id).collect()
+            })
         }
     }
 
+    /// Deliver the next message along with its position in the total order.
+    /// Positions increase with every delivery and are the same on every 
+    /// member. Messages sent directly with `send_to` are not ordered and have
+    /// no position.
+    pub async fn deliver_with_position(&mut self) -> Result<(M, Option<MessagePriority>), MulticastError> {
+        match self.deliver_rcv.recv().await {
+            Some(delivery) => delivery,
+            None => Err(MulticastError::InternalError)
+        }
+    }
+
+    /// What has been sent to each member this node has connected to so far,
+    /// including members that joined later.
+    pub fn connection_stats(&self) -> HashMap<NodeId, ConnectionStats> {
+        self.stats.snapshot()
+    }
+}
+
+#[async_trait]
+impl<M> Multicast<M> for TotalOrderedMulticast<M> where M: Send + Serialize + Clone {
+    async fn connect(node_id: NodeId, config: Config, timeout_secs: u64) -> Result<Self, ConnectError> where M: 'static + Serialize + Send + DeserializeOwned { 
+        MulticastBuilder::new(node_id, config)
+            .connect_timeout(Duration::from_secs(timeout_secs))
+            .total_order()
+            .await
+    }
+
     async fn broadcast(&mut self, msg: M) -> Result<(), MulticastError> where M: Serialize { 
-        self.broadcast_queue.send(msg).unwrap();
-        self.broadcast_queue.recv().await.unwrap()
+        self.broadcast_queue.send(msg).map_err(|_| MulticastError::InternalError)?;
+        self.broadcast_queue.recv().await.unwrap_or(Err(MulticastError::InternalError))
     }
 
     async fn send_to(&mut self, msg: M, recipient: NodeId) -> Result<(), MulticastError> where M: Serialize { 
-        self.send_queue.send((msg, recipient)).unwrap();
-        self.send_queue.recv().await.unwrap()
+        self.send_queue.send((msg, recipient)).map_err(|_| MulticastError::InternalError)?;
+        self.send_queue.recv().await.unwrap_or(Err(MulticastError::InternalError))
     }
 
     async fn deliver(&mut self) -> Result<M, MulticastError> where M: Send + Serialize { 
-        match self.deliver_rcv.recv().await {
-            Some(msg) => Ok(msg),
-            None => unreachable!()
-        }
+        self.deliver_with_position().await.map(|(msg, _)| msg)
     }
 }
@@ -0,0 +1,149 @@
+use tokio::{
+    io::{AsyncRead, AsyncWrite, ReadBuf},
+    net::{TcpStream, TcpListener, UnixStream, UnixListener, lookup_host}
+};
+use std::{
+    fmt, io, net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr}, path::PathBuf, pin::Pin, task::{Context, Poll}
+};
+
+/// Where a member listens for connections from the rest of the group.
+#[derive(Clone, Debug, PartialEq, Eq, Hash)]
+pub enum Address {
+    /// TCP over IPv4 or IPv6. `host` is a host name or an IP address.
+    Tcp { host: String, port: u16 },
+    /// A Unix domain socket, for members running on the same machine.
+    Unix(PathBuf)
+}
+
+impl fmt::Display for Address {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Address::Tcp { host, port } if host.contains(':') => write!(f, "[{host}]:{port}"),
+            Address::Tcp { host, port } => write!(f, "{host}:{port}"),
+            Address::Unix(path) => write!(f, "unix:{}", path.display())
+        }
+    }
+}
+
+impl Address {
+    /// The name a TLS server at this address is asked for. Members check
+    /// certificates rather than host names, so any valid name does.
+    pub(crate) fn server_name(&self) -> &str {
+        match self {
+            Address::Tcp { host, .. } => host,
+            Address::Unix(_) => "localhost"
+        }
+    }
+
+    pub(crate) async fn connect(&self) -> io::Result<Stream> {
+        match self {
+            Address::Tcp { host, port } => TcpStream::connect((host.as_str(), *port)).await.map(Stream::Tcp),
+            Address::Unix(path) => UnixStream::connect(path).await.map(Stream::Unix)
+        }
+    }
+
+    /// The address to listen on for connections to this one, on `bind_ip` if
+    /// given, and otherwise on every interface of the family `host` resolves
+    /// to.
+    async fn bind_addr(host: &str, port: u16, bind_ip: Option<IpAddr>) -> SocketAddr {
+        let ip = match bind_ip {
+            Some(ip) => ip,
+            None => match lookup_host((host, port)).await.ok().and_then(|mut addrs| addrs.next()) {
+                Some(SocketAddr::V6(_)) => Ipv6Addr::UNSPECIFIED.into(),
+                _ => Ipv4Addr::UNSPECIFIED.into()
+            }
+        };
+        SocketAddr::new(ip, port)
+    }
+}
+
+/// A connection to another member, over any transport.
+pub(crate) enum Stream {
+    Tcp(TcpStream),
+    Unix(UnixStream)
+}
+
+impl AsyncRead for Stream {
+    fn poll_read(self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &mut ReadBuf<'_>) -> Poll<io::Result<()>> {
+        match self.get_mut() {
+            Stream::Tcp(s) => Pin::new(s).poll_read(cx, buf),
+            Stream::Unix(s) => Pin::new(s).poll_read(cx, buf)
+        }
+    }
+}
+
+impl AsyncWrite for Stream {
+    fn poll_write(self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &[u8]) -> Poll<io::Result<usize>> {
+        match self.get_mut() {
+            Stream::Tcp(s) => Pin::new(s).poll_write(cx, buf),
+            Stream::Unix(s) => Pin::new(s).poll_write(cx, buf)
+        }
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        match self.get_mut() {
+            Stream::Tcp(s) => Pin::new(s).poll_flush(cx),
+            Stream::Unix(s) => Pin::new(s).poll_flush(cx)
+        }
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        match self.get_mut() {
+            Stream::Tcp(s) => Pin::new(s).poll_shutdown(cx),
+            Stream::Unix(s) => Pin::new(s).poll_shutdown(cx)
+        }
+    }
+}
+
+/// Listens for connections from other members, over any transport.
+pub(crate) enum Listener {
+    Tcp(TcpListener),
+    Unix(UnixListener, PathBuf)
+}
+
+impl Listener {
+    /// Listen at `address`. A Unix socket left behind by a node that is no
+    /// longer running is replaced.
+    pub async fn bind(address: &Address, bind_ip: Option<IpAddr>) -> io::Result<Self> {
+        match address {
+            Address::Tcp { host, port } => {
+                let addr = Address::bind_addr(host, *port, bind_ip).await;
+                TcpListener::bind(addr).await.map(Listener::Tcp)
+            },
+            Address::Unix(path) => {
+                let listener = match UnixListener::bind(path) {
+                    Err(e) if e.kind() == io::ErrorKind::AddrInUse && UnixStream::connect(path).await.is_err() => {
+                        std::fs::remove_file(path)?;
+                        UnixListener::bind(path)
+                    },
+                    listener => listener
+                };
+                listener.map(|listener| Listener::Unix(listener, path.clone()))
+            }
+        }
+    }
+
+    /// The next connection, and a description of where it came from.
+    pub async fn accept(&self) -> io::Result<(Stream, String)> {
+        match self {
+            Listener::Tcp(listener) => {
+                let (stream, addr) = listener.accept().await?;
+                Ok((Stream::Tcp(stream), addr.to_string()))
+            },
+            // Peers connecting over Unix sockets rarely have an address of
+            // their own
+            Listener::Unix(listener, path) => {
+                let (stream, _) = listener.accept().await?;
+                Ok((Stream::Unix(stream), format!("unix:{}", path.display())))
+            }
+        }
+    }
+}
+
+impl Drop for Listener {
+    fn drop(&mut self) {
+        if let Listener::Unix(_, path) = self {
+            let _ = std::fs::remove_file(path);
+        }
+    }
+}
@@ -0,0 +1,402 @@
+use super::MessagePriority;
+use serde::{Serialize, de::DeserializeOwned};
+use tokio::sync::oneshot;
+use log::error;
+use std::{
+    future::Future,
+    fs::{self, File, OpenOptions}, io::{self, Read, Write, Seek, SeekFrom, BufReader, ErrorKind},
+    path::{Path, PathBuf}, sync::mpsc::{self, RecvTimeoutError}, thread, time::{Duration, Instant}
+};
+
+/// When the delivery log forces its writes to disk.
+#[derive(Clone, Copy, Debug)]
+pub enum SyncPolicy {
+    /// Sync every message before it is delivered.
+    Always,
+    /// Sync after every `n` messages.
+    Every(usize),
+    /// Sync at most this long after a message is written.
+    Interval(Duration),
+    /// Never sync explicitly and leave flushing to the operating system.
+    Never
+}
+
+/// An append-only file of every message a `TotalOrderedMulticast` delivered,
+/// each stored with its agreed priority. Replaying the log rebuilds the state
+/// an application derived from those deliveries.
+///
+/// Each record is a little-endian `u32` length followed by the bincode
+/// encoding of `(MessagePriority, M)`. A record cut short by a crash is
+/// discarded when the log is opened.
+///
+/// The file is written by a thread of its own, so that writes and syncs never
+/// hold up other tasks. Clones of a log write to the same file, in the order
+/// their writes were made.
+#[derive(Clone)]
+pub struct DeliveryLog {
+    writes: mpsc::Sender<LogWrite>,
+    last_position: Option<MessagePriority>
+}
+
+/// A request to the thread that writes the log, answered once it is done.
+enum LogWrite {
+    Append(Vec<u8>, oneshot::Sender<io::Result<()>>),
+    Checkpoint { record: Vec<u8>, position: MessagePriority, done: oneshot::Sender<io::Result<()>> }
+}
+
+/// Read the next record from `rdr`, returning `None` at the end of the log or
+/// at a record that was only partially written.
+fn read_record<R: Read>(rdr: &mut R) -> io::Result<Option<Vec<u8>>> {
+    let mut len = [0u8; 4];
+    match rdr.read_exact(&mut len) {
+        Ok(()) => (),
+        Err(e) if e.kind() == ErrorKind::UnexpectedEof => return Ok(None),
+        Err(e) => return Err(e)
+    }
+
+    // The buffer only grows as bytes are read, so a corrupt length cannot make
+    // replay allocate more than is left in the file
+    let len = u32::from_le_bytes(len) as u64;
+    let mut record = Vec::new();
+    rdr.take(len).read_to_end(&mut record)?;
+    match record.len() as u64 == len {
+        true => Ok(Some(record)),
+        false => Ok(None)
+    }
+}
+
+/// Encode `msg` as a record at `position`, length prefix included.
+fn encode_record<M: Serialize>(msg: &M, position: MessagePriority) -> io::Result<Vec<u8>> {
+    let record = bincode::serialize(&(position, msg))
+        .map_err(|e| io::Error::new(ErrorKind::InvalidData, e))?;
+
+    let mut buf = Vec::with_capacity(4 + record.len());
+    buf.extend_from_slice(&(record.len() as u32).to_le_bytes());
+    buf.extend_from_slice(&record);
+    Ok(buf)
+}
+
+/// Sync the directory holding `path`, so that a file renamed to `path` is
+/// still there after a crash.
+fn sync_dir(path: &Path) -> io::Result<()> {
+    let dir = match path.parent() {
+        Some(dir) if !dir.as_os_str().is_empty() => dir,
+        _ => Path::new(".")
+    };
+    File::open(dir)?.sync_all()
+}
+
+/// The file behind a `DeliveryLog`, owned by the thread that writes it.
+struct LogWriter {
+    path: PathBuf,
+    file: File,
+    policy: SyncPolicy,
+    unsynced: usize,
+    last_sync: Instant
+}
+
+impl LogWriter {
+    fn append(&mut self, record: &[u8]) -> io::Result<()> {
+        self.file.write_all(record)?;
+        self.unsynced += 1;
+
+        let should_sync = match self.policy {
+            SyncPolicy::Always => true,
+            SyncPolicy::Every(n) => self.unsynced >= n,
+            SyncPolicy::Interval(d) => self.last_sync.elapsed() >= d,
+            SyncPolicy::Never => false
+        };
+        match should_sync {
+            true => self.sync(),
+            false => Ok(())
+        }
+    }
+
+    fn sync(&mut self) -> io::Result<()> {
+        self.file.sync_data()?;
+        self.unsynced = 0;
+        self.last_sync = Instant::now();
+        Ok(())
+    }
+
+    /// Rewrite the log as `record` followed by every record after `position`.
+    /// The new log is written beside the old one and then moved over it, so a
+    /// crash leaves one or the other.
+    fn checkpoint(&mut self, record: &[u8], position: MessagePriority) -> io::Result<()> {
+        let kept = self.records_after(position);
+        // Later appends go to the end of the old log if it is kept
+        self.file.seek(SeekFrom::End(0))?;
+        let kept = [record.to_vec(), kept?].concat();
+
+        let mut tmp_path = self.path.clone().into_os_string();
+        tmp_path.push(".tmp");
+        let mut file = OpenOptions::new().read(true).write(true).create(true).truncate(true).open(&tmp_path)?;
+        file.write_all(&kept)?;
+        file.sync_data()?;
+        fs::rename(&tmp_path, &self.path)?;
+        sync_dir(&self.path)?;
+
+        self.file = file;
+        self.unsynced = 0;
+        self.last_sync = Instant::now();
+        Ok(())
+    }
+
+    /// Every record after `position`, length prefixes included.
+    fn records_after(&mut self, position: MessagePriority) -> io::Result<Vec<u8>> {
+        let mut kept = Vec::new();
+        self.file.seek(SeekFrom::Start(0))?;
+        let mut rdr = BufReader::new(&mut self.file);
+        while let Some(existing) = read_record(&mut rdr)? {
+            match bincode::deserialize::<MessagePriority>(&existing) {
+                Ok(p) if p <= position => (),
+                Ok(_) => {
+                    kept.extend_from_slice(&(existing.len() as u32).to_le_bytes());
+                    kept.extend_from_slice(&existing);
+                },
+                Err(_) => break
+            }
+        }
+        Ok(kept)
+    }
+
+    /// How long until unsynced writes are due to be synced, if they ever are.
+    fn sync_due(&self) -> Option<Duration> {
+        match self.policy {
+            SyncPolicy::Interval(d) if self.unsynced > 0 => Some(d.saturating_sub(self.last_sync.elapsed())),
+            _ => None
+        }
+    }
+
+    fn run(mut self, writes: mpsc::Receiver<LogWrite>) {
+        loop {
+            let write = match self.sync_due() {
+                Some(due) => writes.recv_timeout(due),
+                None => writes.recv().map_err(|_| RecvTimeoutError::Disconnected)
+            };
+            match write {
+                Ok(LogWrite::Append(record, done)) => {
+                    let _ = done.send(self.append(&record));
+                },
+                Ok(LogWrite::Checkpoint { record, position, done }) => {
+                    let _ = done.send(self.checkpoint(&record, position));
+                },
+                Err(RecvTimeoutError::Timeout) => {
+                    if let Err(e) = self.sync() {
+                        error!("Failed to sync delivery log {}: {}", self.path.display(), e);
+                    }
+                },
+                Err(RecvTimeoutError::Disconnected) => {
+                    if self.unsynced > 0 && !matches!(self.policy, SyncPolicy::Never) {
+                        let _ = self.sync();
+                    }
+                    return;
+                }
+            }
+        }
+    }
+}
+
+impl DeliveryLog {
+    /// Open the log at `path` for appending, creating it if it does not exist.
+    pub fn open<P: AsRef<Path>>(path: P, policy: SyncPolicy) -> io::Result<Self> {
+        let mut file = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create(true)
+            .truncate(false)
+            .open(&path)?;
+
+        // Find the end of the last complete record and drop anything after it
+        let mut valid_len = 0;
+        let mut last_position = None;
+        let mut rdr = BufReader::new(&mut file);
+        while let Some(record) = read_record(&mut rdr)? {
+            match bincode::deserialize::<MessagePriority>(&record) {
+                Ok(position) => last_position = Some(position),
+                Err(_) => break
+            }
+            valid_len += 4 + record.len() as u64;
+        }
+        drop(rdr);
+
+        file.set_len(valid_len)?;
+        file.seek(SeekFrom::End(0))?;
+
+        let writer = LogWriter {
+            path: path.as_ref().to_path_buf(),
+            file,
+            policy,
+            unsynced: 0,
+            last_sync: Instant::now()
+        };
+        let (writes, writes_rcv) = mpsc::channel();
+        thread::Builder::new()
+            .name("delivery-log".into())
+            .spawn(move || writer.run(writes_rcv))?;
+
+        Ok(Self { writes, last_position })
+    }
+
+    /// Read every message in the log at `path` in delivery order.
+    pub fn replay<M, P>(path: P) -> io::Result<Vec<(M, MessagePriority)>> where M: DeserializeOwned, P: AsRef<Path> {
+        let mut rdr = match File::open(path) {
+            Ok(f) => BufReader::new(f),
+            Err(e) if e.kind() == ErrorKind::NotFound => return Ok(Vec::new()),
+            Err(e) => return Err(e)
+        };
+
+        let mut messages = Vec::new();
+        while let Some(record) = read_record(&mut rdr)? {
+            match bincode::deserialize::<(MessagePriority, M)>(&record) {
+                Ok((position, msg)) => messages.push((msg, position)),
+                Err(_) => break
+            }
+        }
+
+        Ok(messages)
+    }
+
+    /// The position of the last message in the log.
+    pub fn last_position(&self) -> Option<MessagePriority> {
+        self.last_position
+    }
+
+    /// Hand a write to the writer thread and wait for it to be done.
+    async fn write(&self, write: impl FnOnce(oneshot::Sender<io::Result<()>>) -> LogWrite) -> io::Result<()> {
+        let (done, done_rcv) = oneshot::channel();
+        let stopped = || io::Error::other("the delivery log writer stopped");
+        self.writes.send(write(done)).map_err(|_| stopped())?;
+        done_rcv.await.map_err(|_| stopped())?
+    }
+
+    /// Append `msg` at `position`. The message is encoded before this returns,
+    /// so the future does not borrow it.
+    pub(crate) fn append<M: Serialize>(&mut self, msg: &M, position: MessagePriority) -> impl Future<Output = io::Result<()>> + '_ {
+        let record = encode_record(msg, position);
+        async move {
+            let record = record?;
+            self.write(|done| LogWrite::Append(record, done)).await?;
+            self.last_position = Some(position);
+            Ok(())
+        }
+    }
+
+    /// Replace every record at or before `position` with a single record of
+    /// `state`, which must reflect all the messages delivered up to there.
+    /// Replaying the log then yields `state` first. Used when an application
+    /// takes its state from another member, for example after joining a
+    /// running group, so that the log no longer holds a gap.
+    pub async fn checkpoint<M: Serialize>(&self, state: &M, position: MessagePriority) -> io::Result<()> {
+        let record = encode_record(state, position)?;
+        self.write(|done| LogWrite::Checkpoint { record, position, done }).await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde::Deserialize;
+
+    #[derive(Debug, PartialEq, Serialize, Deserialize)]
+    enum Entry {
+        Deposit(u32),
+        Balance(u32)
+    }
+
+    /// A path for a log of a test's own, removed when dropped.
+    struct TempLog(PathBuf);
+
+    impl TempLog {
+        fn new(name: &str) -> Self {
+            let path = std::env::temp_dir().join(format!("multicast-wal-{}-{}.log", std::process::id(), name));
+            let _ = fs::remove_file(&path);
+            Self(path)
+        }
+    }
+
+    impl Drop for TempLog {
+        fn drop(&mut self) {
+            let _ = fs::remove_file(&self.0);
+        }
+    }
+
+    fn at(priority: usize) -> MessagePriority {
+        MessagePriority { priority, proposer: 0 }
+    }
+
+    fn replay(path: &Path) -> Vec<(Entry, usize)> {
+        DeliveryLog::replay::<Entry, _>(path)
+            .unwrap()
+            .into_iter()
+            .map(|(entry, position)| (entry, position.priority))
+            .collect()
+    }
+
+    async fn write_deposits(path: &Path, priorities: std::ops::Range<usize>) -> DeliveryLog {
+        let mut log = DeliveryLog::open(path, SyncPolicy::Always).unwrap();
+        for priority in priorities {
+            log.append(&Entry::Deposit(priority as u32), at(priority)).await.unwrap();
+        }
+        log
+    }
+
+    #[tokio::test]
+    async fn replays_in_order() {
+        let log = TempLog::new("replay");
+        assert!(replay(&log.0).is_empty());
+
+        let written = write_deposits(&log.0, 1..4).await;
+        assert_eq!(written.last_position().map(|p| p.priority), Some(3));
+        drop(written);
+        assert_eq!(replay(&log.0), [(Entry::Deposit(1), 1), (Entry::Deposit(2), 2), (Entry::Deposit(3), 3)]);
+
+        let reopened = write_deposits(&log.0, 4..5).await;
+        assert_eq!(reopened.last_position().map(|p| p.priority), Some(4));
+        assert_eq!(replay(&log.0).len(), 4);
+    }
+
+    #[tokio::test]
+    async fn drops_partial_record() {
+        let log = TempLog::new("partial");
+        drop(write_deposits(&log.0, 1..3).await);
+
+        // A crash in the middle of writing a third record
+        let mut file = OpenOptions::new().append(true).open(&log.0).unwrap();
+        file.write_all(&[40, 0, 0, 0, 1, 2]).unwrap();
+        drop(file);
+        assert_eq!(replay(&log.0).len(), 2);
+
+        let reopened = write_deposits(&log.0, 3..4).await;
+        assert_eq!(reopened.last_position().map(|p| p.priority), Some(3));
+        assert_eq!(replay(&log.0), [(Entry::Deposit(1), 1), (Entry::Deposit(2), 2), (Entry::Deposit(3), 3)]);
+    }
+
+    #[tokio::test]
+    async fn drops_record_longer_than_file() {
+        let log = TempLog::new("too-long");
+        drop(write_deposits(&log.0, 1..2).await);
+
+        // A corrupt length that would otherwise allocate 4 GiB
+        let mut file = OpenOptions::new().append(true).open(&log.0).unwrap();
+        file.write_all(&[0xff, 0xff, 0xff, 0xff, 7]).unwrap();
+        drop(file);
+        assert_eq!(replay(&log.0), [(Entry::Deposit(1), 1)]);
+
+        drop(write_deposits(&log.0, 2..3).await);
+        assert_eq!(replay(&log.0), [(Entry::Deposit(1), 1), (Entry::Deposit(2), 2)]);
+    }
+
+    #[tokio::test]
+    async fn checkpoint_replaces_earlier_records() {
+        let log = TempLog::new("checkpoint");
+        let mut written = write_deposits(&log.0, 1..5).await;
+
+        written.checkpoint(&Entry::Balance(3), at(2)).await.unwrap();
+        assert_eq!(replay(&log.0), [(Entry::Balance(3), 2), (Entry::Deposit(3), 3), (Entry::Deposit(4), 4)]);
+
+        written.append(&Entry::Deposit(5), at(5)).await.unwrap();
+        assert_eq!(replay(&log.0).last(), Some(&(Entry::Deposit(5), 5)));
+        assert_eq!(replay(&log.0).len(), 4);
+    }
+}